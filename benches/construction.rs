@@ -0,0 +1,86 @@
+/* Generic tree structures for storage of spatial data.
+ * Copyright (C) 2023  Alexander Pyattaev
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+use spatialtree::*;
+
+/// All positions of a full octree level, in Morton order
+fn sorted_positions(depth: u8) -> Vec<OctVec> {
+    let side = (1u8 << depth) - 1;
+    let mut positions: Vec<OctVec> = iter_all_positions_in_bounds(
+        OctVec::build(0, 0, 0, depth),
+        OctVec::build(side, side, side, depth),
+    )
+    .filter(|p| p.depth == depth)
+    .collect();
+    positions.sort_by(|a, b| a.morton_cmp(b));
+    positions
+}
+
+fn bulk_construction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk construction");
+    group.significance_level(0.1).sample_size(10);
+
+    for depth in [5u8, 7] {
+        let positions = sorted_positions(depth);
+        group.bench_with_input(
+            BenchmarkId::new("insert_many", depth),
+            &positions,
+            |b, positions| {
+                b.iter(|| {
+                    let mut tree = OctTree::<u32, OctVec>::new();
+                    tree.insert_many(positions.iter().copied(), |p| p.pos[0] as u32);
+                    black_box(tree)
+                });
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("from_sorted", depth),
+            &positions,
+            |b, positions| {
+                b.iter(|| {
+                    let tree = OctTree::<u32, OctVec>::from_sorted(
+                        positions.iter().map(|&p| (p, p.pos[0] as u32)),
+                    );
+                    black_box(tree)
+                });
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("from_unsorted", depth),
+            &positions,
+            |b, positions| {
+                b.iter_batched(
+                    || {
+                        positions
+                            .iter()
+                            .rev()
+                            .map(|&p| (p, p.pos[0] as u32))
+                            .collect::<Vec<_>>()
+                    },
+                    |chunks| black_box(OctTree::<u32, OctVec>::from_unsorted(chunks)),
+                    BatchSize::LargeInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bulk_construction);
+criterion_main!(benches);
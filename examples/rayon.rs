@@ -15,8 +15,9 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-/*use spatialtree::*;
 use rayon::prelude::*;
+use spatialtree::*;
+use std::collections::HashMap;
 
 struct Chunk {
     // data to store in the chunk, for EG storing voxel or heighmap data
@@ -25,75 +26,75 @@ struct Chunk {
 }
 
 impl Chunk {
-    // this does a cheap init so it can safely be put inside the vec
-    fn new(_position: OctVec) -> Self {
-        Self { data: [0.0; 4096] }
-    }
-
     // pretend this inits the data with some expensive procedural generation
-    fn expensive_init(&mut self, _position: OctVec) {
-        self.data = [1.0; 4096];
-
+    fn expensive_init(_position: OctVec) -> Self {
         // emulate a 1ms time to do things
         // we can't use sleep because thats 15ms on windows min
         let start = std::time::Instant::now();
 
         while start.elapsed() < std::time::Duration::from_millis(1) {}
+        Self { data: [1.0; 4096] }
     }
 
     // and pretend this makes chunks visible/invisible
     fn set_visible(&mut self, _visibility: bool) {}
-
-    // and pretend this drops anything for when a chunk is permanently deleted
-    fn cleanup(&mut self) {}
 }
 
 fn main() {
     // create an octree
     let mut tree = OctTree::<Chunk, OctVec>::with_capacity(512, 512);
+    // chunks that were made before, but are not in the tree right now
+    let mut cache = HashMap::<OctVec, Chunk>::new();
 
     // the game loop that runs for 42 iterations
-    for _ in 0..42 {
+    for i in 0..42 {
         let start_time = std::time::Instant::now();
 
-        // get the pending updates
-        if tree.prepare_update(
-            &[OctVec::new(4096, 4096, 4096, 32)], // target position in the tree
-            2,                                    // the amount of detail
-            &mut |position_in_tree| Chunk::new(position_in_tree), // and how we should make a new tree inside the function here. This should be done quickly
-        ) {
+        // target position in the tree, moving a bit every frame
+        let target = OctVec::new([64 + i * 2, 128, 128], 8);
+
+        // get the pending updates, this does not change the tree yet
+        let plan = tree.prepare_update(&[target], 2);
+        if !plan.is_empty() {
             let duration = start_time.elapsed().as_micros();
 
             println!(
-                "Took {} microseconds to get the tree update ready",
-                duration
-            );
-            //TODO: rewrite this to use chunks_add callback which modifies captured Vec and pushes chunk references
-            // in there for thread pool to consume later.
-
-            // if there was an update, we need to first generate new chunks with expensive_init
-            tree.get_chunks_to_add_slice_mut().par_iter_mut().for_each(
-                |ToAddContainer {
-                     position, chunk, ..
-                 }| {
-                    // and run expensive init
-                    chunk.expensive_init(*position);
-                },
+                "Took {} microseconds to get the tree update ready, {} chunks to create",
+                duration,
+                plan.to_create.len()
             );
 
+            // reuse whatever we can from the cache
+            let mut new_chunks: Vec<Option<Chunk>> =
+                plan.to_create.iter().map(|p| cache.remove(p)).collect();
 
-            let start_time = std::time::Instant::now();
-
-            // and don't forget to actually run the update
-            tree.do_update();
+            // and generate the rest in parallel with expensive_init
+            new_chunks
+                .par_iter_mut()
+                .zip(plan.to_create.par_iter())
+                .for_each(|(chunk, position)| {
+                    if chunk.is_none() {
+                        *chunk = Some(Chunk::expensive_init(*position));
+                    }
+                });
 
-            // now we probably want to truly clean up the chunks that are going to be deleted from memory
-            for chunk in tree.iter_chunks_to_delete_mut() {
-                chunk.cleanup();
-            }
+            let start_time = std::time::Instant::now();
 
-            // and actually clean them up
-            tree.complete_update();
+            // and don't forget to actually run the update, chunks go in the same order as in the plan.
+            // Chunks no longer needed go into the cache.
+            tree.do_update(
+                plan,
+                new_chunks.into_iter().map(|c| {
+                    let mut c = c.unwrap();
+                    c.set_visible(true);
+                    c
+                }),
+                |position, mut chunk| {
+                    chunk.set_visible(false);
+                    cache.insert(position, chunk);
+                },
+            )
+            .expect("Tree was not modified since the plan was made");
 
             let duration = start_time.elapsed().as_micros();
 
@@ -105,9 +106,13 @@ fn main() {
         println!("Took {} microseconds to do the entire update", duration);
 
         // and print some data about the run
-        println!("Num chunks in the tree: {}", tree.get_num_chunks());
+        println!(
+            "Num chunks in the tree: {}, in the cache: {}",
+            tree.get_num_chunks(),
+            cache.len()
+        );
     }
+    // make sure the data is actually there
+    let total: f32 = tree.iter_chunks().map(|(_, c)| c.chunk.data[0]).sum();
+    assert_eq!(total as usize, tree.get_num_chunks());
 }
-*/
-
-fn main() {}
@@ -0,0 +1,215 @@
+/* Generic tree structures for storage of spatial data.
+ * Copyright (C) 2023  Alexander Pyattaev
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Contains the cache for chunks evicted from the tree during LOD updates.
+
+use crate::coords::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Least-recently-used cache of chunks, keyed by position.
+///
+/// When attached to a tree with Tree::set_cache, chunks evicted by lod_update are stored here,
+/// and reused when their position is needed again instead of calling chunk_creator.
+/// Chunks only ever get "used" by being inserted, so the oldest inserted chunk is dropped first.
+#[derive(Clone, Debug)]
+pub struct ChunkCache<const N: usize, C: Sized, L: LodVec<N>> {
+    /// cached chunks along with their insertion stamp
+    entries: HashMap<L, (u64, C)>,
+    /// positions in the order of insertion
+    order: BTreeMap<u64, L>,
+    /// stamp for the next insertion
+    next_stamp: u64,
+    /// max number of chunks to keep
+    capacity: usize,
+    /// number of chunks reused from the cache
+    hits: u64,
+    /// number of chunks looked up but not found in the cache
+    misses: u64,
+}
+
+impl<const N: usize, C, L> ChunkCache<N, C, L>
+where
+    C: Sized,
+    L: LodVec<N>,
+{
+    /// create an empty cache that will hold at most capacity chunks
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::with_capacity(capacity),
+            order: BTreeMap::new(),
+            next_stamp: 0,
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// max number of chunks the cache will hold
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// number of chunks in the cache
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// true if cache holds no chunks
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// number of chunks that were taken from the cache
+    #[inline]
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// number of chunks that were looked up, but had to be created
+    #[inline]
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// zero the hit and miss counters
+    #[inline]
+    pub fn reset_stats(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    /// true if there is a chunk for position in the cache
+    #[inline]
+    pub fn contains(&self, position: &L) -> bool {
+        self.entries.contains_key(position)
+    }
+
+    /// get a reference to a cached chunk, if any. Does not affect the stats.
+    #[inline]
+    pub fn get(&self, position: &L) -> Option<&C> {
+        self.entries.get(position).map(|(_, c)| c)
+    }
+
+    /// Puts a chunk into the cache. If this makes the cache overflow, the oldest chunk is returned back.
+    /// If there already was a chunk at this position, it is replaced and returned.
+    pub fn insert(&mut self, position: L, chunk: C) -> Option<(L, C)> {
+        if let Some(old) = self.remove(&position) {
+            self.insert(position, chunk);
+            return Some((position, old));
+        }
+        if self.capacity == 0 {
+            return Some((position, chunk));
+        }
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.entries.insert(position, (stamp, chunk));
+        self.order.insert(stamp, position);
+
+        if self.entries.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first()?;
+            let (_, chunk) = self.entries.remove(&oldest)?;
+            return Some((oldest, chunk));
+        }
+        None
+    }
+
+    /// Removes a chunk from the cache in order to reuse it, counting a hit or a miss.
+    #[inline]
+    pub fn take(&mut self, position: L) -> Option<C> {
+        let rv = self.remove(&position);
+        match rv {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        rv
+    }
+
+    /// Removes a chunk from the cache. Does not affect the stats.
+    #[inline]
+    pub fn remove(&mut self, position: &L) -> Option<C> {
+        let (stamp, chunk) = self.entries.remove(position)?;
+        self.order.remove(&stamp);
+        Some(chunk)
+    }
+
+    /// counts misses for chunks made outside of the cache
+    #[inline]
+    pub(crate) fn add_misses(&mut self, misses: usize) {
+        self.misses += misses as u64;
+    }
+
+    /// Removes all chunks from the cache, oldest first.
+    /// Chunks are removed as the iterator advances, so whatever is not consumed stays in the cache.
+    pub fn drain(&mut self) -> impl Iterator<Item = (L, C)> + '_ {
+        std::iter::from_fn(|| {
+            let (_, p) = self.order.pop_first()?;
+            let (_, c) = self.entries.remove(&p).expect("Cache entries out of sync");
+            Some((p, c))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_order() {
+        let mut cache = ChunkCache::<2, u32, QuadVec>::new(2);
+        let a = QuadVec::build(0, 0, 1);
+        let b = QuadVec::build(1, 0, 1);
+        let c = QuadVec::build(1, 1, 1);
+        assert_eq!(cache.insert(a, 1), None);
+        assert_eq!(cache.insert(b, 2), None);
+        // a is the oldest, so it goes
+        assert_eq!(cache.insert(c, 3), Some((a, 1)));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&a));
+
+        // reinserting refreshes the entry
+        assert_eq!(cache.insert(b, 4), Some((b, 2)));
+        assert_eq!(cache.insert(a, 5), Some((c, 3)));
+        assert_eq!(cache.get(&b), Some(&4));
+
+        assert_eq!(cache.take(a), Some(5));
+        assert_eq!(cache.take(a), None);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        cache.reset_stats();
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+
+        cache.insert(c, 6);
+        assert!(cache.drain().eq([(b, 4), (c, 6)]));
+        assert!(cache.is_empty());
+
+        // partially consumed drain leaves the rest in a usable cache
+        cache.insert(a, 7);
+        cache.insert(b, 8);
+        assert_eq!(cache.drain().next(), Some((a, 7)));
+        assert_eq!(cache.len(), 1);
+        cache.insert(c, 9);
+        assert_eq!(cache.insert(a, 10), Some((b, 8)));
+        assert_eq!(cache.len(), 2);
+
+        // zero capacity cache keeps nothing
+        let mut cache = ChunkCache::<2, u32, QuadVec>::new(0);
+        assert_eq!(cache.insert(a, 1), Some((a, 1)));
+        assert!(cache.is_empty());
+    }
+}
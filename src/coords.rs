@@ -56,6 +56,106 @@ pub trait LodVec<const N: usize>:
 
     /// Retrieve current depth
     fn depth(self) -> u8;
+
+    /// returns the ancestor of this node at a given depth (or self if depth is equal to current).
+    /// Returns None if depth is greater than current depth.
+    fn ancestor_at_depth(self, depth: u8) -> Option<Self>;
+
+    /// returns the parent of this node, or None for the root.
+    #[inline]
+    fn parent(self) -> Option<Self> {
+        self.depth()
+            .checked_sub(1)
+            .and_then(|d| self.ancestor_at_depth(d))
+    }
+
+    /// returns the deepest node that contains both a and b (which may be one of them).
+    #[inline]
+    fn common_ancestor(a: Self, b: Self) -> Self {
+        let mut depth = a.depth().min(b.depth());
+        loop {
+            let aa = a.ancestor_at_depth(depth).expect("depth is not above a");
+            if Some(aa) == b.ancestor_at_depth(depth) || depth == 0 {
+                return aa;
+            }
+            depth -= 1;
+        }
+    }
+
+    /// iterates over the path from the root to this node, both ends included.
+    #[inline]
+    fn path_from_root(self) -> impl Iterator<Item = Self> {
+        (0..=self.depth()).filter_map(move |d| self.ancestor_at_depth(d))
+    }
+
+    /// returns the node at the same depth, shifted by offset (in nodes along each axis).
+    /// Returns None if the result would be outside of the tree.
+    ///
+    /// Default implementation walks the path from the root and back, assuming that bit i of a child index
+    /// selects the upper half along axis i (as in CoordVec). Override it if coordinates are at hand.
+    fn neighbor(self, offset: [i64; N]) -> Option<Self> {
+        let depth = self.depth();
+        // coordinates along each axis, in nodes of this depth
+        let mut coords = [0i64; N];
+        let mut node = Self::root();
+        for d in 1..=depth {
+            let child = self.ancestor_at_depth(d)?;
+            let index = node.get_child_index(child);
+            for (i, c) in coords.iter_mut().enumerate() {
+                *c = (*c << 1) | ((index >> i) & 1) as i64;
+            }
+            node = child;
+        }
+        let size = 1i64 << depth;
+        for (c, o) in coords.iter_mut().zip(offset) {
+            *c = c.checked_add(o).filter(|c| (0..size).contains(c))?;
+        }
+        let mut rv = Self::root();
+        for d in (0..depth).rev() {
+            let index = coords
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (((c >> d) & 1) as usize) << i)
+                .sum();
+            rv = rv.get_child(index);
+        }
+        Some(rv)
+    }
+
+    /// iterates over the face neighbors of this node (up to 2N of them), skipping the ones outside the tree.
+    /// Order is -1 then +1 along the first axis, then -1 and +1 along the second, etc.
+    #[inline]
+    fn face_neighbors(self) -> impl Iterator<Item = Self> {
+        (0..2 * N).filter_map(move |i| self.neighbor(face_offset(i)))
+    }
+
+    /// iterates over all face, edge and corner neighbors of this node (up to 3^N-1 of them),
+    /// skipping the ones outside the tree.
+    #[inline]
+    fn all_neighbors(self) -> impl Iterator<Item = Self> {
+        (0..3usize.pow(N as u32)).filter_map(move |mut i| {
+            let mut offset = [0i64; N];
+            for o in offset.iter_mut() {
+                *o = (i % 3) as i64 - 1;
+                i /= 3;
+            }
+            if offset.iter().all(|&o| o == 0) {
+                return None;
+            }
+            self.neighbor(offset)
+        })
+    }
+}
+
+/// Offset towards a given face of a node, as used by LodVec::neighbor.
+/// Faces are numbered -1 then +1 along the first axis, then -1 and +1 along the second, etc,
+/// so there are 2N of them.
+#[inline]
+pub fn face_offset<const N: usize>(face: usize) -> [i64; N] {
+    debug_assert!(face < 2 * N);
+    let mut offset = [0i64; N];
+    offset[face / 2] = if face & 1 == 0 { -1 } else { 1 };
+    offset
 }
 
 /// Trait for data types suitable for use in CoordVec.
@@ -158,6 +258,95 @@ where
     }
 }
 
+impl<const N: usize, DT> CoordVec<N, DT>
+where
+    DT: ReasonableIntegerLike,
+{
+    /// index of child at given level (0 is the root's child) that contains this node, same as in get_child.
+    #[inline(always)]
+    fn child_index_at_level(self, level: u8) -> usize {
+        let shift = self.depth - 1 - level;
+        self.pos
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, e)| acc | (((e.tousize() >> shift) & 1) << i))
+    }
+
+    /// Morton (Z-order) code of the position, with bits of all axes interleaved.
+    /// The code is the sequence of child indices (as in get_child) from the root down to this node,
+    /// so it is only unique among nodes of the same depth. N * depth must not exceed 128.
+    #[inline]
+    pub fn morton_encode(self) -> u128 {
+        debug_assert!(N * self.depth as usize <= 128, "Morton code does not fit");
+        (0..self.depth).fold(0, |code, level| {
+            (code << N) | self.child_index_at_level(level) as u128
+        })
+    }
+
+    /// Reconstructs position from Morton code and depth (reciprocal of morton_encode)
+    #[inline]
+    pub fn morton_decode(code: u128, depth: u8) -> Self {
+        debug_assert!(N * depth as usize <= 128, "Morton code does not fit");
+        let mask = (1 << N) - 1;
+        (0..depth).fold(Self::root(), |node, level| {
+            let shift = N * (depth - 1 - level) as usize;
+            node.get_child(((code >> shift) & mask) as usize)
+        })
+    }
+
+    /// Total order consistent with the depth-first traverse of the tree (children visited in index order).
+    /// Positions are compared in Morton order, and ancestors come before their descendants.
+    /// Works for any depth, unlike comparing Morton codes directly.
+    #[inline]
+    pub fn morton_cmp(&self, other: &Self) -> Ordering {
+        for level in 0..self.depth.min(other.depth) {
+            let a = self.child_index_at_level(level);
+            let b = other.child_index_at_level(level);
+            if a != b {
+                return a.cmp(&b);
+            }
+        }
+        self.depth.cmp(&other.depth)
+    }
+}
+
+/// Wrapper around CoordVec that is totally ordered by CoordVec::morton_cmp,
+/// to allow sorting positions and using them as keys in ordered collections such as BTreeMap.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, std::hash::Hash)]
+pub struct MortonKey<const N: usize, DT = u8>(pub CoordVec<N, DT>)
+where
+    DT: ReasonableIntegerLike;
+
+impl<const N: usize, DT> PartialOrd for MortonKey<N, DT>
+where
+    DT: ReasonableIntegerLike,
+{
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize, DT> Ord for MortonKey<N, DT>
+where
+    DT: ReasonableIntegerLike,
+{
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.morton_cmp(&other.0)
+    }
+}
+
+impl<const N: usize, DT> From<CoordVec<N, DT>> for MortonKey<N, DT>
+where
+    DT: ReasonableIntegerLike,
+{
+    #[inline]
+    fn from(value: CoordVec<N, DT>) -> Self {
+        Self(value)
+    }
+}
+
 impl<const N: usize, DT> LodVec<N> for CoordVec<N, DT>
 where
     DT: ReasonableIntegerLike,
@@ -244,6 +433,31 @@ where
                 .all(|(slf, (min, max))| slf >= min && slf <= max)
     }
 
+    #[inline]
+    fn ancestor_at_depth(self, depth: u8) -> Option<Self> {
+        let level_difference = self.depth.checked_sub(depth)?;
+        Some(Self {
+            pos: self
+                .pos
+                .map(|e| DT::fromusize(e.tousize() >> level_difference)),
+            depth,
+        })
+    }
+
+    #[inline]
+    fn neighbor(self, offset: [i64; N]) -> Option<Self> {
+        let size = 1i64 << self.depth;
+        let mut new = self;
+        for (p, o) in new.pos.iter_mut().zip(offset) {
+            let np = (p.tousize() as i64).checked_add(o)?;
+            if np < 0 || np >= size {
+                return None;
+            }
+            *p = DT::fromusize(np as usize);
+        }
+        Some(new)
+    }
+
     #[inline(always)]
     fn can_subdivide(self, node: Self, detail: u32) -> bool {
         let detail = detail as usize;
@@ -349,6 +563,19 @@ where
     }
 }
 
+/// Signed offset between two positions at the same depth, in cells of that depth.
+impl<const N: usize, DT> std::ops::Sub for CoordVec<N, DT>
+where
+    DT: ReasonableIntegerLike,
+{
+    type Output = [i64; N];
+    #[inline]
+    fn sub(self, rhs: Self) -> Self::Output {
+        debug_assert_eq!(self.depth, rhs.depth);
+        std::array::from_fn(|i| self.pos[i].tousize() as i64 - rhs.pos[i].tousize() as i64)
+    }
+}
+
 impl<const N: usize, DT> Default for CoordVec<N, DT>
 where
     DT: ReasonableIntegerLike,
@@ -441,4 +668,160 @@ mod tests {
         println!("{tgt:?}, {c12:?}, {}", tgt.can_subdivide(c12, 3));
         println!("{tgt:?}, {c1:?}, {}", tgt.can_subdivide(c1, 3));
     }
+
+    #[test]
+    fn neighbors() {
+        let c: QuadVec = QuadVec::build(1, 3, 2);
+        assert_eq!(c.neighbor([1, 0]), Some(QuadVec::build(2, 3, 2)));
+        assert_eq!(c.neighbor([-1, -2]), Some(QuadVec::build(0, 1, 2)));
+        assert_eq!(c.neighbor([-2, 0]), None);
+        assert_eq!(c.neighbor([0, 1]), None);
+        assert_eq!(c.neighbor([0, 0]), Some(c));
+        assert_eq!(QuadVec::<u8>::root().neighbor([1, 0]), None);
+
+        // on the border only some of the neighbors exist
+        let faces: Vec<QuadVec> = c.face_neighbors().collect();
+        assert_eq!(
+            faces,
+            [
+                QuadVec::build(0, 3, 2),
+                QuadVec::build(2, 3, 2),
+                QuadVec::build(1, 2, 2)
+            ]
+        );
+        assert_eq!(c.all_neighbors().count(), 5);
+
+        let inner = OctVec::<u16>::build(5, 5, 5, 4);
+        assert_eq!(inner.face_neighbors().count(), 6);
+        let all: Vec<_> = inner.all_neighbors().collect();
+        assert_eq!(all.len(), 26);
+        for n in all {
+            assert_ne!(n, inner);
+            assert!(n.pos.iter().all(|&p| (4..=6).contains(&p)));
+        }
+        assert_eq!(OctVec::<u8>::root().all_neighbors().count(), 0);
+
+        assert_eq!(
+            QuadVec::<u8>::build(3, 2, 2) - QuadVec::build(1, 2, 2),
+            [2, 0]
+        );
+        assert_eq!(
+            QuadVec::<u8>::build(1, 0, 2) - QuadVec::build(2, 3, 2),
+            [-1, -3]
+        );
+        // offset leads back to the other position
+        let (a, b) = (OctVec::<u16>::build(7, 0, 9, 4), OctVec::build(8, 1, 8, 4));
+        assert_eq!(a.neighbor(b - a), Some(b));
+        assert_eq!(a.neighbor([i64::MAX, 0, 0]), None);
+    }
+
+    /// LodVec that relies on the default neighbor implementation
+    #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd)]
+    struct Wrapped(OctVec);
+
+    impl LodVec<3> for Wrapped {
+        fn get_child(self, index: usize) -> Self {
+            Self(self.0.get_child(index))
+        }
+        fn get_child_index(self, child: Self) -> usize {
+            self.0.get_child_index(child.0)
+        }
+        fn contains_child_node(self, child: Self) -> bool {
+            self.0.contains_child_node(child.0)
+        }
+        fn root() -> Self {
+            Self(OctVec::root())
+        }
+        fn can_subdivide(self, node: Self, detail: u32) -> bool {
+            self.0.can_subdivide(node.0, detail)
+        }
+        fn is_inside_bounds(self, min: Self, max: Self, max_depth: u8) -> bool {
+            self.0.is_inside_bounds(min.0, max.0, max_depth)
+        }
+        fn depth(self) -> u8 {
+            self.0.depth
+        }
+        fn ancestor_at_depth(self, depth: u8) -> Option<Self> {
+            self.0.ancestor_at_depth(depth).map(Self)
+        }
+    }
+
+    #[test]
+    fn default_neighbor() {
+        let c = OctVec::build(5, 0, 7, 3);
+        for x in -8..8 {
+            for y in -2..2 {
+                let offset = [x, y, -1];
+                assert_eq!(
+                    Wrapped(c).neighbor(offset).map(|w| w.0),
+                    c.neighbor(offset),
+                    "{offset:?}"
+                );
+            }
+        }
+        assert_eq!(Wrapped::root().neighbor([0, 0, 0]), Some(Wrapped::root()));
+        assert_eq!(Wrapped::root().neighbor([1, 0, 0]), None);
+    }
+
+    #[test]
+    fn ancestors() {
+        let root = OctVec::<u16>::root();
+        assert_eq!(root.parent(), None);
+        assert_eq!(root.ancestor_at_depth(0), Some(root));
+        assert_eq!(root.ancestor_at_depth(1), None);
+        assert!(root.path_from_root().eq([root]));
+
+        let c = OctVec::<u16>::build(5, 2, 7, 3);
+        assert_eq!(c.parent(), Some(OctVec::build(2, 1, 3, 2)));
+        assert_eq!(c.ancestor_at_depth(1), Some(OctVec::build(1, 0, 1, 1)));
+        assert_eq!(c.ancestor_at_depth(3), Some(c));
+        assert_eq!(c.ancestor_at_depth(4), None);
+
+        let path: Vec<_> = c.path_from_root().collect();
+        assert_eq!(path.len(), 4);
+        assert_eq!(path[0], root);
+        assert_eq!(path[3], c);
+        for w in path.windows(2) {
+            assert_eq!(w[1].parent(), Some(w[0]));
+            assert!(w[0].contains_child_node(w[1]));
+            assert_eq!(w[0].get_child(w[0].get_child_index(w[1])), w[1]);
+        }
+
+        let d = OctVec::build(4, 3, 6, 3);
+        assert_eq!(OctVec::common_ancestor(c, d), OctVec::build(2, 1, 3, 2));
+        assert_eq!(OctVec::common_ancestor(c, path[1]), path[1]);
+        assert_eq!(OctVec::common_ancestor(c, OctVec::build(0, 0, 0, 3)), root);
+        assert_eq!(OctVec::common_ancestor(root, c), root);
+    }
+
+    #[test]
+    fn morton() {
+        let c = QuadVec::<u8>::build(5, 3, 3);
+        // x = 101, y = 011, interleaved from the top as yx pairs: 01 10 11
+        assert_eq!(c.morton_encode(), 0b01_10_11);
+        assert_eq!(QuadVec::morton_decode(0b01_10_11, 3), c);
+        assert_eq!(QuadVec::<u8>::root().morton_encode(), 0);
+
+        let c = OctVec::<u64>::build(123456789, 987654321, 55555, 40);
+        assert_eq!(OctVec::morton_decode(c.morton_encode(), 40), c);
+
+        // depth-first traverse of the whole tree must come out sorted
+        let mut dfs = vec![];
+        let mut stack = vec![QuadVec::<u8>::root()];
+        while let Some(p) = stack.pop() {
+            dfs.push(p);
+            if p.depth < 3 {
+                stack.extend((0..4).rev().map(|i| p.get_child(i)));
+            }
+        }
+        assert_eq!(dfs.len(), 1 + 4 + 16 + 64);
+        let mut sorted: Vec<_> = dfs.iter().rev().copied().map(MortonKey).collect();
+        sorted.sort();
+        assert!(sorted.iter().map(|k| k.0).eq(dfs.iter().copied()));
+
+        // and at same depth Morton codes give the same order
+        for w in dfs.windows(2).filter(|w| w[0].depth == w[1].depth) {
+            assert!(w[0].morton_encode() < w[1].morton_encode());
+        }
+    }
 }
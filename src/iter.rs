@@ -97,16 +97,26 @@ where
     L: LodVec<N>,
 {
     pub fn new(nodes: &'a NodeStorage<B>, bound_min: L, bound_max: L) -> Self {
+        let root = TreePos {
+            idx: 0,
+            pos: L::root(),
+        };
+        Self::with_start(nodes, root, bound_min, bound_max)
+    }
+
+    /// Same as new, but only looks at the subtree starting from a given node.
+    pub(crate) fn with_start(
+        nodes: &'a NodeStorage<B>,
+        start: TreePos<N, L>,
+        bound_min: L,
+        bound_max: L,
+    ) -> Self {
         debug_assert_eq!(bound_min.depth(), bound_max.depth());
 
         // TODO: Smallvec?
         let mut to_visit = Vec::with_capacity(Self::stack_size(bound_min));
 
-        to_visit.push(TreePos {
-            idx: 0,
-            pos: L::root(),
-        });
-
+        to_visit.push(start);
 
         ChunkIdxInAABBIter {
             to_visit,
@@ -264,7 +274,7 @@ where
         &'a self,
         bound_min: L,
         bound_max: L,
-    ) -> ChunkIdxInAABBIter<N, B, L> {
+    ) -> ChunkIdxInAABBIter<'a, N, B, L> {
         ChunkIdxInAABBIter::new(&self.nodes, bound_min, bound_max)
     }
 
@@ -274,7 +284,7 @@ where
         &'a self,
         bound_min: L,
         bound_max: L,
-    ) -> ChunksInAABBIter<N, B, C, L> {
+    ) -> ChunksInAABBIter<'a, N, B, C, L> {
         ChunksInAABBIter {
             chunks: &self.chunks,
              chunk_idx_iter: ChunkIdxInAABBIter::new(&self.nodes, bound_min, bound_max),
@@ -286,12 +296,782 @@ where
         &'a mut self,
         bound_min: L,
         bound_max: L,
-    ) -> ChunksInAABBIterMut<N, B, C, L> {
+    ) -> ChunksInAABBIterMut<'a, N, B, C, L> {
         ChunksInAABBIterMut {
             chunks: &mut self.chunks,
             chunk_idx_iter: ChunkIdxInAABBIter::new(&self.nodes, bound_min, bound_max),
         }
     }
+
+    /// Removes all chunks in the bounding box (same ones as iter_chunks_in_aabb would visit),
+    /// and returns an iterator over them along with their positions.
+    /// Nodes inside the bounding box that are left empty are removed in the same pass.
+    pub fn drain_aabb(&mut self, bound_min: L, bound_max: L) -> std::vec::IntoIter<(L, C)> {
+        debug_assert_eq!(bound_min.depth(), bound_max.depth());
+        let max_depth = bound_min.depth();
+        let mut rv = Vec::new();
+        self.remove_chunks_inner(
+            max_depth,
+            |pos| pos.is_inside_bounds(bound_min, bound_max, max_depth),
+            |_, _| false,
+            |pos, chunk| rv.push((pos, chunk)),
+        );
+        rv.into_iter()
+    }
+}
+
+/// A chunk hit by a ray, along with ray parameter values where it enters and exits the chunk.
+#[derive(Clone, Debug, Copy)]
+pub struct RayHit<const N: usize, L: LodVec<N>> {
+    /// chunk index and position
+    pub pos: TreePos<N, L>,
+    /// ray parameter at which the ray enters the chunk (or 0 if it starts inside)
+    pub t_enter: f32,
+    /// ray parameter at which the ray exits the chunk (or max_t if it ends inside)
+    pub t_exit: f32,
+}
+
+/// Intersects ray with an axis-aligned cell, returning entry and exit parameters clipped to [0, max_t].
+#[inline]
+fn ray_cell_intersection<const N: usize, DT: ReasonableIntegerLike>(
+    cell: CoordVec<N, DT>,
+    origin: &[f32; N],
+    inv_direction: &[f32; N],
+    max_t: f32,
+) -> Option<(f32, f32)> {
+    let lo = cell.float_coords();
+    let size = cell.float_size();
+    let mut t_enter = 0.0f32;
+    let mut t_exit = max_t;
+    for i in 0..N {
+        if inv_direction[i].is_infinite() {
+            // ray parallel to this axis, it is either always inside the slab or never
+            if origin[i] < lo[i] || origin[i] >= lo[i] + size {
+                return None;
+            }
+            continue;
+        }
+        let t1 = (lo[i] - origin[i]) * inv_direction[i];
+        let t2 = (lo[i] + size - origin[i]) * inv_direction[i];
+        t_enter = t_enter.max(t1.min(t2));
+        t_exit = t_exit.min(t1.max(t2));
+    }
+    // touching a cell in a single point does not count
+    (t_enter < t_exit).then_some((t_enter, t_exit))
+}
+
+/// Iterator over indices of chunks pierced by a ray, in front-to-back order.
+/// When chunks overlap (i.e. chunks at different depths), the coarser one is returned first.
+pub struct ChunkIdxOnRayIter<'a, const N: usize, const B: usize, DT>
+where
+    DT: ReasonableIntegerLike,
+{
+    /// the reference to tree's nodes
+    nodes: &'a NodeStorage<B>,
+    /// stack of child slots to visit, nearest on top
+    to_visit: Vec<(usize, RayHit<N, CoordVec<N, DT>>)>,
+    /// where the ray starts
+    origin: [f32; N],
+    /// reciprocal of ray direction (for faster slab tests)
+    inv_direction: [f32; N],
+    /// how far along the ray to look
+    max_t: f32,
+}
+
+impl<'a, const N: usize, const B: usize, DT> ChunkIdxOnRayIter<'a, N, B, DT>
+where
+    DT: ReasonableIntegerLike,
+{
+    /// Constructs the iterator. Ray is defined in the normalized space where the root covers [0,1] in every
+    /// dimension, same as used by CoordVec::from_float_coords. Ray parameter t is measured in units of
+    /// direction's length, only points with 0 <= t <= max_t are considered.
+    pub fn new(
+        nodes: &'a NodeStorage<B>,
+        origin: [f32; N],
+        direction: [f32; N],
+        max_t: f32,
+    ) -> Self {
+        debug_assert!(
+            direction.iter().any(|d| *d != 0.0),
+            "Ray direction can not be zero"
+        );
+        let mut ite = ChunkIdxOnRayIter {
+            nodes,
+            to_visit: Vec::with_capacity(B * 4),
+            origin,
+            inv_direction: direction.map(|d| 1.0 / d),
+            max_t,
+        };
+        let root = CoordVec::root();
+        if ray_cell_intersection(root, &ite.origin, &ite.inv_direction, max_t).is_some() {
+            ite.push_children(0, root);
+        }
+        ite
+    }
+
+    /// Pushes all existing child slots of a node that the ray passes through, nearest on top of the stack.
+    #[inline]
+    fn push_children(&mut self, node_idx: usize, node_pos: CoordVec<N, DT>) {
+        let node = &self.nodes[node_idx];
+        let mut hits = arrayvec::ArrayVec::<(usize, RayHit<N, CoordVec<N, DT>>), B>::new();
+        for b in 0..B {
+            let chunk = node.chunk[b].get();
+            if chunk.is_none() && node.children[b].is_none() {
+                continue;
+            }
+            let child_pos = node_pos.get_child(b);
+            if let Some((t_enter, t_exit)) =
+                ray_cell_intersection(child_pos, &self.origin, &self.inv_direction, self.max_t)
+            {
+                hits.push((
+                    b,
+                    RayHit {
+                        // chunk index, or usize::MAX if this slot only has a child node
+                        pos: TreePos {
+                            idx: chunk.unwrap_or(usize::MAX),
+                            pos: child_pos,
+                        },
+                        t_enter,
+                        t_exit,
+                    },
+                ));
+            }
+        }
+        // sibling cells do not overlap, so sorting them by entry point gives front-to-back order
+        hits.sort_unstable_by(|a, b| b.1.t_enter.total_cmp(&a.1.t_enter));
+        for (b, hit) in hits {
+            let child = node.children[b].map_or(usize::MAX, |c| c.get() as usize);
+            self.to_visit.push((child, hit));
+        }
+    }
+}
+
+impl<'a, const N: usize, const B: usize, DT> Iterator for ChunkIdxOnRayIter<'a, N, B, DT>
+where
+    DT: ReasonableIntegerLike,
+{
+    type Item = RayHit<N, CoordVec<N, DT>>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (child, hit) = self.to_visit.pop()?;
+            // everything inside the child cell is further than the chunk in this slot,
+            // but closer than anything else on the stack
+            if child != usize::MAX {
+                self.push_children(child, hit.pos.pos);
+            }
+            if hit.pos.idx != usize::MAX {
+                return Some(hit);
+            }
+        }
+    }
+}
+
+/// Iterator over chunks pierced by a ray, in front-to-back order.
+pub struct ChunksOnRayIter<'a, const N: usize, const B: usize, C, DT>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+{
+    // the chunks storage reference
+    chunks: &'a ChunkStorage<N, C, CoordVec<N, DT>>,
+    // iterator over indices in chunk storage
+    chunk_idx_iter: ChunkIdxOnRayIter<'a, N, B, DT>,
+}
+
+impl<'a, const N: usize, const B: usize, C, DT> Iterator for ChunksOnRayIter<'a, N, B, C, DT>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+{
+    type Item = (RayHit<N, CoordVec<N, DT>>, &'a C);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let hit = self.chunk_idx_iter.next()?;
+        Some((hit, &self.chunks[hit.pos.idx].chunk))
+    }
+}
+
+impl<'a, const N: usize, const B: usize, C, DT> Tree<N, B, C, CoordVec<N, DT>>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+    Self: 'a,
+{
+    /// Iterate over all chunks pierced by a ray, in front-to-back order.
+    /// Ray is defined in the normalized space where the tree covers [0,1] in every dimension.
+    /// Only the part of the ray with 0 <= t <= max_t is considered, t is in units of direction's length.
+    #[inline(always)]
+    pub fn iter_chunks_on_ray(
+        &'a self,
+        origin: [f32; N],
+        direction: [f32; N],
+        max_t: f32,
+    ) -> ChunksOnRayIter<'a, N, B, C, DT> {
+        ChunksOnRayIter {
+            chunks: &self.chunks,
+            chunk_idx_iter: ChunkIdxOnRayIter::new(&self.nodes, origin, direction, max_t),
+        }
+    }
+
+    /// Find the first chunk hit by a ray. See iter_chunks_on_ray for details.
+    #[inline]
+    pub fn raycast(
+        &'a self,
+        origin: [f32; N],
+        direction: [f32; N],
+        max_t: f32,
+    ) -> Option<(RayHit<N, CoordVec<N, DT>>, &'a C)> {
+        self.iter_chunks_on_ray(origin, direction, max_t).next()
+    }
+}
+
+/// Half-space used for frustum culling: points x with dot(normal, x) + offset >= 0 are inside.
+/// Coordinates are in the normalized space where the tree covers [0,1] in every dimension.
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct ClipPlane<const N: usize> {
+    /// normal of the plane, pointing towards the inside. Does not need to be unit length.
+    pub normal: [f32; N],
+    /// signed offset of the plane
+    pub offset: f32,
+}
+
+impl<const N: usize> ClipPlane<N> {
+    /// creates a plane from normal and offset
+    #[inline]
+    pub fn new(normal: [f32; N], offset: f32) -> Self {
+        Self { normal, offset }
+    }
+
+    /// creates a plane passing through a point, with normal pointing towards the inside
+    #[inline]
+    pub fn from_point_normal(point: [f32; N], normal: [f32; N]) -> Self {
+        let offset = -point.iter().zip(normal).map(|(p, n)| p * n).sum::<f32>();
+        Self { normal, offset }
+    }
+
+    /// signed distance to a point (scaled by length of the normal)
+    #[inline]
+    pub fn distance(&self, point: [f32; N]) -> f32 {
+        point
+            .iter()
+            .zip(self.normal)
+            .map(|(p, n)| p * n)
+            .sum::<f32>()
+            + self.offset
+    }
+}
+
+/// How a chunk relates to a query region
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum Containment {
+    /// chunk is entirely inside the region
+    Inside,
+    /// chunk is only partially inside the region
+    Intersecting,
+}
+
+/// A region of space that can be used to select chunks, e.g. a view frustum or a sphere.
+/// Coordinates are in the normalized space where the tree covers [0,1] in every dimension.
+pub trait QueryRegion<const N: usize> {
+    /// Classifies a cell with lowest corner at lo and given edge size against the region.
+    /// Returns None if the cell is completely outside of the region.
+    /// Cells that merely touch the region should be considered outside.
+    fn classify_cell(&self, lo: [f32; N], size: f32) -> Option<Containment>;
+}
+
+impl<const N: usize, R: QueryRegion<N> + ?Sized> QueryRegion<N> for &R {
+    #[inline]
+    fn classify_cell(&self, lo: [f32; N], size: f32) -> Option<Containment> {
+        (**self).classify_cell(lo, size)
+    }
+}
+
+/// A set of clip planes selects the convex region inside all of them.
+impl<const N: usize> QueryRegion<N> for [ClipPlane<N>] {
+    #[inline]
+    fn classify_cell(&self, lo: [f32; N], size: f32) -> Option<Containment> {
+        let mut containment = Containment::Inside;
+        for plane in self {
+            // corners of the cell furthest along and against the normal
+            let mut far = lo;
+            let mut near = lo;
+            for i in 0..N {
+                if plane.normal[i] > 0.0 {
+                    far[i] += size;
+                } else {
+                    near[i] += size;
+                }
+            }
+            // merely touching the plane does not count
+            if plane.distance(far) <= 0.0 {
+                return None;
+            }
+            if plane.distance(near) < 0.0 {
+                containment = Containment::Intersecting;
+            }
+        }
+        Some(containment)
+    }
+}
+
+/// Sphere (or circle for quadtrees) for radius queries.
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct Sphere<const N: usize> {
+    /// center of the sphere
+    pub center: [f32; N],
+    /// radius of the sphere
+    pub radius: f32,
+}
+
+impl<const N: usize> Sphere<N> {
+    /// creates a sphere from float coordinates, where the tree covers [0,1] in every dimension.
+    #[inline]
+    pub fn new(center: [f32; N], radius: f32) -> Self {
+        Self { center, radius }
+    }
+
+    /// creates a sphere around the center of a cell, with radius measured in cells at the depth of that cell.
+    #[inline]
+    pub fn from_coords<DT: ReasonableIntegerLike>(center: CoordVec<N, DT>, radius: u32) -> Self {
+        let size = center.float_size();
+        Self {
+            center: center.float_coords().map(|c| c + size * 0.5),
+            radius: radius as f32 * size,
+        }
+    }
+}
+
+impl<const N: usize> QueryRegion<N> for Sphere<N> {
+    #[inline]
+    fn classify_cell(&self, lo: [f32; N], size: f32) -> Option<Containment> {
+        // squared distances to the nearest and the furthest points of the cell
+        let mut near = 0.0f32;
+        let mut far = 0.0f32;
+        for (c, l) in self.center.iter().zip(lo) {
+            let d_lo = c - l;
+            let d_hi = l + size - c;
+            let d_near = (-d_lo).max(-d_hi).max(0.0);
+            let d_far = d_lo.abs().max(d_hi.abs());
+            near += d_near * d_near;
+            far += d_far * d_far;
+        }
+        let r2 = self.radius * self.radius;
+        // merely touching the sphere does not count
+        if near >= r2 {
+            return None;
+        }
+        if far <= r2 {
+            Some(Containment::Inside)
+        } else {
+            Some(Containment::Intersecting)
+        }
+    }
+}
+
+///Iterator over positions and indices of chunks inside a query region (e.g. a view frustum or a sphere).
+pub struct ChunkIdxInRegionIter<'a, const N: usize, const B: usize, DT, R>
+where
+    DT: ReasonableIntegerLike,
+    R: QueryRegion<N>,
+{
+    /// the reference to tree's nodes
+    nodes: &'a NodeStorage<B>,
+
+    /// the region to select
+    region: R,
+
+    /// internal stack for tree traverse, along with flag telling if node is fully inside
+    to_visit: Vec<(TreePos<N, CoordVec<N, DT>>, bool)>,
+
+    /// chunks to return
+    to_return: arrayvec::ArrayVec<(TreePos<N, CoordVec<N, DT>>, Containment), B>,
+}
+
+impl<'a, const N: usize, const B: usize, DT, R> ChunkIdxInRegionIter<'a, N, B, DT, R>
+where
+    DT: ReasonableIntegerLike,
+    R: QueryRegion<N>,
+{
+    pub fn new(nodes: &'a NodeStorage<B>, region: R) -> Self {
+        let mut to_visit = Vec::with_capacity(B * 4);
+        let root = CoordVec::root();
+        if let Some(c) = region.classify_cell(root.float_coords(), root.float_size()) {
+            to_visit.push((TreePos { idx: 0, pos: root }, c == Containment::Inside));
+        }
+        ChunkIdxInRegionIter {
+            nodes,
+            region,
+            to_visit,
+            to_return: arrayvec::ArrayVec::new(),
+        }
+    }
+}
+
+impl<'a, const N: usize, const B: usize, DT, R> Iterator for ChunkIdxInRegionIter<'a, N, B, DT, R>
+where
+    DT: ReasonableIntegerLike,
+    R: QueryRegion<N>,
+{
+    type Item = (TreePos<N, CoordVec<N, DT>>, Containment);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        // if we have nothing in to_return stack, traverse the tree.
+        while self.to_return.is_empty() {
+            let (current, inside) = self.to_visit.pop()?;
+            let cur_node = &self.nodes[current.idx];
+            for i in 0..B {
+                let chunk = cur_node.chunk[i].get();
+                let child = cur_node.children[i];
+                if chunk.is_none() && child.is_none() {
+                    continue;
+                }
+                let child_position = current.pos.get_child(i);
+                // once a cell is fully inside, so are all of its children
+                let containment = if inside {
+                    Containment::Inside
+                } else {
+                    match self
+                        .region
+                        .classify_cell(child_position.float_coords(), child_position.float_size())
+                    {
+                        Some(c) => c,
+                        None => continue,
+                    }
+                };
+                if let Some(child_idx) = child {
+                    self.to_visit.push((
+                        TreePos {
+                            pos: child_position,
+                            idx: child_idx.get() as usize,
+                        },
+                        containment == Containment::Inside,
+                    ));
+                }
+                if let Some(chunk_idx) = chunk {
+                    self.to_return.push((
+                        TreePos {
+                            pos: child_position,
+                            idx: chunk_idx,
+                        },
+                        containment,
+                    ));
+                }
+            }
+        }
+        self.to_return.pop()
+    }
+}
+
+duplicate::duplicate! {
+    [
+        StructName                 reference(lt, type)     getter(p);
+        [ChunksInRegionIter]       [& 'lt type]             [ &self.chunks[p.idx].chunk ];
+        // SAFETY: we attach the lifetime of the iterator to this when returning so
+        // nobody can destroy the tree when we are not looking.
+        [ChunksInRegionIterMut]    [& 'lt mut type]         [unsafe{self.chunks[p.idx].chunk_ptr().as_mut().unwrap_unchecked()}];
+    ]
+
+    ///Iterator over positions and chunks inside a query region.
+    pub struct StructName<'a, const N:usize, const B:usize, C, DT, R>
+    where
+    DT:ReasonableIntegerLike,
+    C:Sized,
+    R:QueryRegion<N>,
+    {
+        // the chunks storage reference
+        chunks: reference([a],[ChunkStorage<N,C,CoordVec<N, DT>>]),
+        // iterator over indices in chunk storage
+        chunk_idx_iter: ChunkIdxInRegionIter<'a, N, B, DT, R>,
+    }
+    impl  <'a, const N:usize, const B:usize, C, DT, R> Iterator for StructName<'a, N, B, C, DT, R> where
+    DT:ReasonableIntegerLike,
+    C:Sized,
+    R:QueryRegion<N>,
+    {
+        type Item = (TreePos<N, CoordVec<N, DT>>, Containment, reference([a], [C]));
+
+        #[inline]
+        fn next(&mut self) -> Option<Self::Item> {
+            // fetch next position from position iterator
+            let (pos, containment) = self.chunk_idx_iter.next()?;
+            // return appropriate reference to the chunk
+            Some((pos, containment, getter([pos])))
+        }
+    }
+}
+
+///Iterator over positions and indices of chunks inside a convex region bounded by clip planes (e.g. a view frustum).
+pub type ChunkIdxInFrustumIter<'a, const N: usize, const B: usize, DT> =
+    ChunkIdxInRegionIter<'a, N, B, DT, &'a [ClipPlane<N>]>;
+///Iterator over positions and chunks inside a convex region bounded by clip planes.
+pub type ChunksInFrustumIter<'a, const N: usize, const B: usize, C, DT> =
+    ChunksInRegionIter<'a, N, B, C, DT, &'a [ClipPlane<N>]>;
+///Iterator over positions and mutable chunks inside a convex region bounded by clip planes.
+pub type ChunksInFrustumIterMut<'a, const N: usize, const B: usize, C, DT> =
+    ChunksInRegionIterMut<'a, N, B, C, DT, &'a [ClipPlane<N>]>;
+///Iterator over positions and chunks inside a sphere.
+pub type ChunksInSphereIter<'a, const N: usize, const B: usize, C, DT> =
+    ChunksInRegionIter<'a, N, B, C, DT, Sphere<N>>;
+///Iterator over positions and mutable chunks inside a sphere.
+pub type ChunksInSphereIterMut<'a, const N: usize, const B: usize, C, DT> =
+    ChunksInRegionIterMut<'a, N, B, C, DT, Sphere<N>>;
+
+impl<'a, const N: usize, const B: usize, C, DT> Tree<N, B, C, CoordVec<N, DT>>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+    Self: 'a,
+{
+    /// Iterate over references to all chunks inside a query region. Also tells if each chunk is fully inside,
+    /// or only intersects the boundary.
+    #[inline(always)]
+    pub fn iter_chunks_in_region<R: QueryRegion<N>>(
+        &'a self,
+        region: R,
+    ) -> ChunksInRegionIter<'a, N, B, C, DT, R> {
+        ChunksInRegionIter {
+            chunks: &self.chunks,
+            chunk_idx_iter: ChunkIdxInRegionIter::new(&self.nodes, region),
+        }
+    }
+
+    /// Iterate over mutable references to all chunks inside a query region. Also tells if each chunk is fully inside,
+    /// or only intersects the boundary.
+    #[inline(always)]
+    pub fn iter_chunks_in_region_mut<R: QueryRegion<N>>(
+        &'a mut self,
+        region: R,
+    ) -> ChunksInRegionIterMut<'a, N, B, C, DT, R> {
+        ChunksInRegionIterMut {
+            chunks: &mut self.chunks,
+            chunk_idx_iter: ChunkIdxInRegionIter::new(&self.nodes, region),
+        }
+    }
+
+    /// Iterate over references to all chunks inside the region bounded by planes (e.g. a view frustum).
+    /// Also tells if each chunk is fully inside, or only intersects the boundary.
+    #[inline(always)]
+    pub fn iter_chunks_in_frustum(
+        &'a self,
+        planes: &'a [ClipPlane<N>],
+    ) -> ChunksInFrustumIter<'a, N, B, C, DT> {
+        self.iter_chunks_in_region(planes)
+    }
+
+    /// Iterate over mutable references to all chunks inside the region bounded by planes (e.g. a view frustum).
+    /// Also tells if each chunk is fully inside, or only intersects the boundary.
+    #[inline(always)]
+    pub fn iter_chunks_in_frustum_mut(
+        &'a mut self,
+        planes: &'a [ClipPlane<N>],
+    ) -> ChunksInFrustumIterMut<'a, N, B, C, DT> {
+        self.iter_chunks_in_region_mut(planes)
+    }
+
+    /// Iterate over references to all chunks inside a sphere. Also tells if each chunk is fully inside,
+    /// or only intersects the boundary.
+    /// Use Sphere::new for float coordinates, or Sphere::from_coords for a radius in cells around a position.
+    #[inline(always)]
+    pub fn iter_chunks_in_sphere(
+        &'a self,
+        sphere: Sphere<N>,
+    ) -> ChunksInSphereIter<'a, N, B, C, DT> {
+        self.iter_chunks_in_region(sphere)
+    }
+
+    /// Iterate over mutable references to all chunks inside a sphere. Also tells if each chunk is fully inside,
+    /// or only intersects the boundary.
+    #[inline(always)]
+    pub fn iter_chunks_in_sphere_mut(
+        &'a mut self,
+        sphere: Sphere<N>,
+    ) -> ChunksInSphereIterMut<'a, N, B, C, DT> {
+        self.iter_chunks_in_region_mut(sphere)
+    }
+}
+
+/// Entry of the priority queue used by NearestChunksIter, ordered by distance.
+struct NearestEntry<const N: usize, DT: ReasonableIntegerLike> {
+    /// squared distance from the query point to the cell
+    dist2: f32,
+    /// node or chunk index
+    pos: TreePos<N, CoordVec<N, DT>>,
+    /// true if idx refers to a chunk, false if it refers to a node
+    is_chunk: bool,
+}
+
+impl<const N: usize, DT: ReasonableIntegerLike> PartialEq for NearestEntry<N, DT> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl<const N: usize, DT: ReasonableIntegerLike> Eq for NearestEntry<N, DT> {}
+
+impl<const N: usize, DT: ReasonableIntegerLike> PartialOrd for NearestEntry<N, DT> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize, DT: ReasonableIntegerLike> Ord for NearestEntry<N, DT> {
+    /// reversed, so that BinaryHeap pops the nearest entry first. On ties chunks go first.
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other
+            .dist2
+            .total_cmp(&self.dist2)
+            .then(self.is_chunk.cmp(&other.is_chunk))
+    }
+}
+
+/// Squared distance from point to the nearest point of the cell (0 if the point is inside)
+#[inline]
+fn point_cell_dist2<const N: usize, DT: ReasonableIntegerLike>(
+    point: &[f32; N],
+    cell: CoordVec<N, DT>,
+) -> f32 {
+    let lo = cell.float_coords();
+    let size = cell.float_size();
+    point
+        .iter()
+        .zip(lo)
+        .map(|(p, l)| {
+            let d = (l - p).max(p - l - size).max(0.0);
+            d * d
+        })
+        .sum()
+}
+
+/// Iterator over chunks in order of increasing distance from a point, optionally filtered by a predicate.
+/// Performs a best-first traverse of the tree, so only the nodes closer than the returned chunks are visited.
+pub struct NearestChunksIter<'a, const N: usize, const B: usize, C, DT, F>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+    F: FnMut(&C) -> bool,
+{
+    /// the reference to tree's nodes
+    nodes: &'a NodeStorage<B>,
+    /// the chunks storage reference
+    chunks: &'a ChunkStorage<N, C, CoordVec<N, DT>>,
+    /// priority queue of nodes and chunks, nearest on top
+    queue: std::collections::BinaryHeap<NearestEntry<N, DT>>,
+    /// the query point
+    point: [f32; N],
+    /// which chunks we are interested in
+    filter: F,
+}
+
+impl<'a, const N: usize, const B: usize, C, DT, F> Iterator
+    for NearestChunksIter<'a, N, B, C, DT, F>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+    F: FnMut(&C) -> bool,
+{
+    /// position and index of the chunk, distance from the query point, and the chunk
+    type Item = (TreePos<N, CoordVec<N, DT>>, f32, &'a C);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.queue.pop()?;
+            if entry.is_chunk {
+                let chunk = &self.chunks[entry.pos.idx].chunk;
+                return Some((entry.pos, entry.dist2.sqrt(), chunk));
+            }
+            let node = &self.nodes[entry.pos.idx];
+            for b in 0..B {
+                let chunk = node.chunk[b].get();
+                let child = node.children[b];
+                if chunk.is_none() && child.is_none() {
+                    continue;
+                }
+                let child_pos = entry.pos.pos.get_child(b);
+                let dist2 = point_cell_dist2(&self.point, child_pos);
+                if let Some(chunk_idx) = chunk {
+                    if (self.filter)(&self.chunks[chunk_idx].chunk) {
+                        self.queue.push(NearestEntry {
+                            dist2,
+                            pos: TreePos {
+                                idx: chunk_idx,
+                                pos: child_pos,
+                            },
+                            is_chunk: true,
+                        });
+                    }
+                }
+                if let Some(child_idx) = child {
+                    self.queue.push(NearestEntry {
+                        dist2,
+                        pos: TreePos {
+                            idx: child_idx.get() as usize,
+                            pos: child_pos,
+                        },
+                        is_chunk: false,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl<'a, const N: usize, const B: usize, C, DT> Tree<N, B, C, CoordVec<N, DT>>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+    Self: 'a,
+{
+    /// Iterate over chunks accepted by filter in order of increasing distance from a point.
+    /// Point is in the normalized space where the tree covers [0,1] in every dimension,
+    /// distance is measured to the nearest point of each chunk (so it is 0 for chunks containing the point).
+    #[inline]
+    pub fn iter_chunks_by_distance<F>(
+        &'a self,
+        point: [f32; N],
+        filter: F,
+    ) -> NearestChunksIter<'a, N, B, C, DT, F>
+    where
+        F: FnMut(&C) -> bool,
+    {
+        let mut queue = std::collections::BinaryHeap::with_capacity(B * 4);
+        queue.push(NearestEntry {
+            dist2: 0.0,
+            pos: TreePos {
+                idx: 0,
+                pos: CoordVec::root(),
+            },
+            is_chunk: false,
+        });
+        NearestChunksIter {
+            nodes: &self.nodes,
+            chunks: &self.chunks,
+            queue,
+            point,
+            filter,
+        }
+    }
+
+    /// Find up to k chunks accepted by filter that are nearest to a point, sorted by distance.
+    /// See iter_chunks_by_distance for details.
+    #[inline]
+    pub fn nearest_chunks<F>(
+        &'a self,
+        point: [f32; N],
+        k: usize,
+        filter: F,
+    ) -> Vec<(TreePos<N, CoordVec<N, DT>>, f32, &'a C)>
+    where
+        F: FnMut(&C) -> bool,
+    {
+        let mut rv = Vec::with_capacity(k.min(self.chunks.len()));
+        rv.extend(self.iter_chunks_by_distance(point, filter).take(k));
+        rv
+    }
 }
 
 #[cfg(test)]
@@ -409,6 +1189,45 @@ mod tests {
         }
 
     }
+    #[test]
+    fn drain_aabb() {
+        let mut rng = SmallRng::seed_from_u64(42);
+        let mut tree = QuadTree::<usize, QuadVec>::new();
+        let positions: Vec<QuadVec> = (0..200)
+            .map(|_| {
+                let depth = rng.gen_range(1..6u8);
+                let max = (1u8 << depth) - 1;
+                QuadVec::build(rng.gen_range(0..=max), rng.gen_range(0..=max), depth)
+            })
+            .collect();
+        tree.insert_many(positions.iter().copied(), |p| p.pos[0] as usize);
+
+        let min = QuadVec::build(2u8, 3, 4);
+        let max = QuadVec::build(11u8, 9, 4);
+        let mut expected: Vec<_> = tree
+            .iter_chunks_in_aabb(min, max)
+            .map(|(p, c)| (p.pos, *c))
+            .collect();
+        // reference result made the slow way
+        let mut reference = tree.clone();
+        for &(p, _) in expected.iter() {
+            reference.pop_chunk_by_position(p);
+        }
+        reference.prune_nodes();
+
+        let mut drained: Vec<_> = tree.drain_aabb(min, max).collect();
+        expected.sort_by(|a, b| a.0.morton_cmp(&b.0));
+        drained.sort_by(|a, b| a.0.morton_cmp(&b.0));
+        assert!(!drained.is_empty());
+        assert_eq!(drained, expected);
+        assert_eq!(tree.iter_chunks_in_aabb(min, max).count(), 0);
+        assert_eq!(tree.get_num_chunks(), reference.get_num_chunks());
+        assert_eq!(tree.nodes.len(), reference.nodes.len());
+        for (_, c) in reference.iter_chunks() {
+            assert_eq!(tree.get_chunk_by_position(c.position()), Some(&c.chunk));
+        }
+    }
+
     #[test]
     fn iterate_over_chunks_in_aabb() {
         const D: u8 = 4;
@@ -576,4 +1395,247 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn chunks_on_ray() {
+        let mut rng = SmallRng::seed_from_u64(42);
+        let mut tree = QuadTree::<usize, QuadVec>::new();
+        // sparse chunks at a few different depths, some of them overlapping
+        for d in [2u8, 4, 5] {
+            let cmax = (1u8 << d) - 1;
+            let pos_iter = iter_all_positions_in_bounds(
+                QuadVec::new([0, 0], d),
+                QuadVec::new([cmax, cmax], d),
+            )
+            .filter(|p| p.depth == d);
+            let sparse: Vec<_> = pos_iter.filter(|_| rng.gen_bool(0.3)).collect();
+            tree.insert_many(sparse.into_iter(), |p| p.depth as usize);
+        }
+
+        for _ in 0..100 {
+            let origin = [rng.gen_range(-0.5..1.5), rng.gen_range(-0.5..1.5)];
+            let direction = [rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)];
+            let max_t = rng.gen_range(0.1..3.0);
+            let inv_direction = direction.map(|d: f32| 1.0 / d);
+
+            let hits: Vec<_> = tree.iter_chunks_on_ray(origin, direction, max_t).collect();
+            // front-to-back order
+            for w in hits.windows(2) {
+                assert!(w[0].0.t_enter <= w[1].0.t_enter);
+            }
+            // same set of chunks as brute force
+            let mut expected: Vec<_> = tree
+                .iter_chunks()
+                .filter(|(_, c)| {
+                    ray_cell_intersection(c.position(), &origin, &inv_direction, max_t).is_some()
+                })
+                .map(|(i, _)| i)
+                .collect();
+            let mut found: Vec<_> = hits.iter().map(|(h, _)| h.pos.idx).collect();
+            expected.sort();
+            found.sort();
+            assert_eq!(found, expected);
+
+            let first = tree.raycast(origin, direction, max_t);
+            assert_eq!(
+                first.map(|(h, _)| h.pos.idx),
+                hits.first().map(|(h, _)| h.pos.idx)
+            );
+        }
+
+        // axis aligned ray along the bottom row of a full tree
+        let mut tree = QuadTree::<usize, QuadVec>::new();
+        let max = QuadVec::new([3, 3], 2);
+        tree.insert_many(
+            iter_all_positions_in_bounds(QuadVec::new([0, 0], 2), max).filter(|p| p.depth == 2),
+            |p| p.pos[0] as usize,
+        );
+        let xs: Vec<_> = tree
+            .iter_chunks_on_ray([-1.0, 0.1], [1.0, 0.0], 10.0)
+            .map(|(h, c)| {
+                assert_eq!(h.pos.pos.pos[1], 0);
+                *c
+            })
+            .collect();
+        assert_eq!(xs, [0, 1, 2, 3]);
+        let (hit, _) = tree.raycast([0.6, 0.9], [-1.0, 0.0], 10.0).unwrap();
+        assert_eq!(hit.pos.pos, QuadVec::new([2, 3], 2));
+        assert_eq!(hit.t_enter, 0.0);
+        assert!((hit.t_exit - 0.1).abs() < 1e-6);
+        assert!(tree.raycast([0.5, 1.5], [1.0, 0.0], 10.0).is_none());
+    }
+
+    #[test]
+    fn chunks_in_frustum() {
+        let mut rng = SmallRng::seed_from_u64(42);
+        let mut tree = OctTree::<usize, OctVec>::new();
+        for d in [1u8, 3, 4] {
+            let cmax = (1u8 << d) - 1;
+            let pos_iter = iter_all_positions_in_bounds(
+                OctVec::new([0, 0, 0], d),
+                OctVec::new([cmax, cmax, cmax], d),
+            )
+            .filter(|p| p.depth == d);
+            let sparse: Vec<_> = pos_iter.filter(|_| rng.gen_bool(0.2)).collect();
+            tree.insert_many(sparse.into_iter(), |p| p.depth as usize);
+        }
+
+        for _ in 0..50 {
+            // a pyramid looking along x axis from a random point
+            let eye = [
+                rng.gen_range(-0.5..0.5),
+                rng.gen_range(0.0..1.0),
+                rng.gen_range(0.0..1.0),
+            ];
+            let planes = [
+                ClipPlane::from_point_normal(eye, [1.0, 1.0, 0.0]),
+                ClipPlane::from_point_normal(eye, [1.0, -1.0, 0.0]),
+                ClipPlane::from_point_normal(eye, [1.0, 0.0, 1.0]),
+                ClipPlane::from_point_normal(eye, [1.0, 0.0, -1.0]),
+                ClipPlane::from_point_normal([eye[0] + 0.7, 0.0, 0.0], [-1.0, 0.0, 0.0]),
+            ];
+            let mut found: Vec<_> = tree
+                .iter_chunks_in_frustum(&planes)
+                .map(|(p, c, _)| (p.idx, c))
+                .collect();
+            let mut expected: Vec<_> = tree
+                .iter_chunks()
+                .filter_map(|(i, c)| {
+                    let p = c.position();
+                    Some((i, planes.classify_cell(p.float_coords(), p.float_size())?))
+                })
+                .collect();
+            found.sort_by_key(|(i, _)| *i);
+            expected.sort_by_key(|(i, _)| *i);
+            assert_eq!(found, expected);
+        }
+
+        // mutable version visits the same chunks
+        let planes = [ClipPlane::new([1.0, 0.0, 0.0], -0.5)];
+        for (p, c, chunk) in tree.iter_chunks_in_frustum_mut(&planes) {
+            assert!(p.pos.pos[0] as usize >= (1 << p.pos.depth) / 2);
+            assert_eq!(c, Containment::Inside);
+            *chunk = 0;
+        }
+        let n = tree.iter_chunks().filter(|(_, c)| c.chunk == 0).count();
+        assert_eq!(n, tree.iter_chunks_in_frustum(&planes).count());
+    }
+
+    #[test]
+    fn chunks_in_sphere() {
+        let mut rng = SmallRng::seed_from_u64(42);
+        let mut tree = OctTree::<usize, OctVec>::new();
+        for d in [2u8, 3, 4] {
+            let cmax = (1u8 << d) - 1;
+            let pos_iter = iter_all_positions_in_bounds(
+                OctVec::new([0, 0, 0], d),
+                OctVec::new([cmax, cmax, cmax], d),
+            )
+            .filter(|p| p.depth == d);
+            let sparse: Vec<_> = pos_iter.filter(|_| rng.gen_bool(0.3)).collect();
+            tree.insert_many(sparse.into_iter(), |p| p.depth as usize);
+        }
+
+        for _ in 0..50 {
+            let sphere = Sphere::new(
+                [
+                    rng.gen_range(-0.2..1.2),
+                    rng.gen_range(-0.2..1.2),
+                    rng.gen_range(-0.2..1.2),
+                ],
+                rng.gen_range(0.01..0.6),
+            );
+            let mut found: Vec<_> = tree
+                .iter_chunks_in_sphere(sphere)
+                .map(|(p, c, _)| (p.idx, c))
+                .collect();
+            let mut expected: Vec<_> = tree
+                .iter_chunks()
+                .filter_map(|(i, c)| {
+                    let p = c.position();
+                    Some((i, sphere.classify_cell(p.float_coords(), p.float_size())?))
+                })
+                .collect();
+            found.sort_by_key(|(i, _)| *i);
+            expected.sort_by_key(|(i, _)| *i);
+            assert_eq!(found, expected);
+        }
+
+        // sphere defined in cells selects less than its bounding box
+        let mut tree = QuadTree::<usize, QuadVec>::new();
+        let d = 4;
+        tree.insert_many(
+            iter_all_positions_in_bounds(QuadVec::new([0, 0], d), QuadVec::new([15, 15], d))
+                .filter(|p| p.depth == d),
+            |_| 0,
+        );
+        let sphere = Sphere::from_coords(QuadVec::new([8u8, 8], d), 3);
+        assert_eq!(sphere.center, [8.5 / 16.0, 8.5 / 16.0]);
+        assert_eq!(sphere.radius, 3.0 / 16.0);
+        let in_box = tree
+            .iter_chunks_in_aabb(QuadVec::new([5, 5], d), QuadVec::new([11, 11], d))
+            .count();
+        let mut in_sphere = 0;
+        for (p, _, c) in tree.iter_chunks_in_sphere_mut(sphere) {
+            let dx = p.pos.pos[0] as i32 - 8;
+            let dy = p.pos.pos[1] as i32 - 8;
+            assert!(dx * dx + dy * dy <= 3 * 3 + 2 * 3);
+            *c = 1;
+            in_sphere += 1;
+        }
+        assert_eq!(in_box, 49);
+        assert!(in_sphere < in_box);
+        assert_eq!(
+            tree.iter_chunks().filter(|(_, c)| c.chunk == 1).count(),
+            in_sphere
+        );
+    }
+
+    #[test]
+    fn nearest_chunks() {
+        let mut rng = SmallRng::seed_from_u64(42);
+        let mut tree = QuadTree::<usize, QuadVec>::new();
+        for d in [2u8, 4, 5] {
+            let cmax = (1u8 << d) - 1;
+            let pos_iter = iter_all_positions_in_bounds(
+                QuadVec::new([0, 0], d),
+                QuadVec::new([cmax, cmax], d),
+            )
+            .filter(|p| p.depth == d);
+            let sparse: Vec<_> = pos_iter.filter(|_| rng.gen_bool(0.1)).collect();
+            tree.insert_many(sparse.into_iter(), |_| rng.gen_range(0..4));
+        }
+
+        for _ in 0..50 {
+            let point = [rng.gen_range(-0.2..1.2), rng.gen_range(-0.2..1.2)];
+            let k = rng.gen_range(1..10);
+            let found = tree.nearest_chunks(point, k, |c| *c == 0);
+            // brute force
+            let mut expected: Vec<_> = tree
+                .iter_chunks()
+                .filter(|(_, c)| c.chunk == 0)
+                .map(|(_, c)| point_cell_dist2(&point, c.position()).sqrt())
+                .collect();
+            expected.sort_by(|a, b| a.total_cmp(b));
+            expected.truncate(k);
+            let dists: Vec<_> = found.iter().map(|(_, d, _)| *d).collect();
+            assert_eq!(dists, expected);
+            for (p, _, c) in found {
+                assert_eq!(*c, 0);
+                assert_eq!(tree.get_chunk_by_position(p.pos), Some(c));
+            }
+        }
+
+        // everything is returned eventually
+        assert_eq!(
+            tree.iter_chunks_by_distance([0.5, 0.5], |_| true).count(),
+            tree.get_num_chunks()
+        );
+        assert!(tree.nearest_chunks([0.5, 0.5], 3, |c| *c > 10).is_empty());
+        // k is only an upper bound
+        assert_eq!(
+            tree.nearest_chunks([0.5, 0.5], usize::MAX, |_| true).len(),
+            tree.get_num_chunks()
+        );
+    }
 }
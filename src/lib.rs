@@ -27,6 +27,18 @@ pub use crate::util_funcs::*;
 pub mod tree;
 pub use crate::tree::*;
 
+pub mod cache;
+pub use crate::cache::*;
+
 
 pub mod iter;
 pub use crate::iter::*;
+
+pub mod snapshot;
+pub use crate::snapshot::*;
+
+#[cfg(feature = "serde")]
+mod serde_impl;
+
+#[cfg(feature = "rayon")]
+mod rayon_impl;
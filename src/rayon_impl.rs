@@ -0,0 +1,303 @@
+/* Generic tree structures for storage of spatial data.
+ * Copyright (C) 2023  Alexander Pyattaev
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Parallel operations on trees using rayon (enabled with "rayon" feature).
+
+use crate::coords::*;
+use crate::iter::*;
+use crate::tree::*;
+use crate::util_funcs::*;
+use rayon::prelude::*;
+
+/// Pointer to the chunk storage that can be shared between threads.
+struct SharedChunks<const N: usize, C, L: LodVec<N>>(*mut ChunkStorage<N, C, L>);
+
+// SAFETY: chunks themselves are Send, and every thread only touches its own chunks.
+unsafe impl<const N: usize, C: Send, L: LodVec<N>> Send for SharedChunks<N, C, L> {}
+unsafe impl<const N: usize, C: Send, L: LodVec<N>> Sync for SharedChunks<N, C, L> {}
+
+impl<const N: usize, const B: usize, C, L> Tree<N, B, C, L>
+where
+    C: Sized + Sync,
+    L: LodVec<N>,
+{
+    /// Parallel version of iter_chunks, yields chunk indices and containers.
+    #[inline]
+    pub fn par_iter_chunks(
+        &self,
+    ) -> impl ParallelIterator<Item = (usize, &ChunkContainer<N, C, L>)> + '_ {
+        (0..self.chunks.capacity())
+            .into_par_iter()
+            .filter_map(|i| Some((i, self.chunks.get(i)?)))
+    }
+
+    /// Parallel version of iter_chunks_in_aabb. Work is split between subtrees of the root node.
+    #[inline]
+    pub fn par_iter_chunks_in_aabb(
+        &self,
+        bound_min: L,
+        bound_max: L,
+    ) -> impl ParallelIterator<Item = (TreePos<N, L>, &C)> + '_ {
+        Self::par_iter_chunk_indices_in_aabb(&self.nodes, bound_min, bound_max)
+            .map(|p| (p, &self.chunks[p.idx].chunk))
+    }
+}
+
+impl<const N: usize, const B: usize, C, L> Tree<N, B, C, L>
+where
+    C: Sized + Send,
+    L: LodVec<N>,
+{
+    /// Parallel version of iter_chunks_mut, yields chunk indices and containers.
+    /// References to all chunks are collected up front, so this allocates.
+    #[inline]
+    pub fn par_iter_chunks_mut(
+        &mut self,
+    ) -> impl ParallelIterator<Item = (usize, &mut ChunkContainer<N, C, L>)> + '_ {
+        self.chunks.iter_mut().collect::<Vec<_>>().into_par_iter()
+    }
+
+    /// Parallel version of iter_chunks_in_aabb_mut. Work is split between subtrees of the root node,
+    /// so every thread gets its own set of chunks.
+    #[inline]
+    pub fn par_iter_chunks_in_aabb_mut(
+        &mut self,
+        bound_min: L,
+        bound_max: L,
+    ) -> impl ParallelIterator<Item = (TreePos<N, L>, &mut C)> + '_ {
+        let chunks = SharedChunks(&mut self.chunks as *mut ChunkStorage<N, C, L>);
+        Self::par_iter_chunk_indices_in_aabb(&self.nodes, bound_min, bound_max).map(move |p| {
+            let chunks = &chunks;
+            // SAFETY: every chunk is referred to by exactly one node slot, so all indices are distinct,
+            // and every chunk is handed out at most once. The storage is not resized or otherwise touched
+            // while the iterator lives, as we hold &mut self for that long.
+            let chunk = unsafe { (*chunks.0).get_unchecked_mut(p.idx).chunk_ptr() };
+            (p, unsafe { &mut *chunk })
+        })
+    }
+}
+
+impl<const N: usize, const B: usize, C, L> Tree<N, B, C, L>
+where
+    C: Sized,
+    L: LodVec<N>,
+{
+    /// Positions and indices of chunks in the AABB, with one task per child of the root.
+    /// Each task returns the root's chunk in its slot followed by the subtree under it.
+    fn par_iter_chunk_indices_in_aabb<'a>(
+        nodes: &'a NodeStorage<B>,
+        bound_min: L,
+        bound_max: L,
+    ) -> impl ParallelIterator<Item = TreePos<N, L>> + 'a
+    where
+        L: 'a,
+    {
+        let root = &nodes[0];
+        (0..B).into_par_iter().flat_map_iter(move |b| {
+            let child_pos = L::root().get_child(b);
+            let in_bounds = child_pos.is_inside_bounds(bound_min, bound_max, bound_min.depth());
+            let chunk = root.chunk[b]
+                .get()
+                .filter(|_| in_bounds)
+                .map(|idx| TreePos {
+                    idx,
+                    pos: child_pos,
+                });
+            let subtree = root.children[b].filter(|_| in_bounds).map(|idx| {
+                let start = TreePos {
+                    idx: idx.get() as usize,
+                    pos: child_pos,
+                };
+                ChunkIdxInAABBIter::with_start(nodes, start, bound_min, bound_max)
+            });
+            chunk.into_iter().chain(subtree.into_iter().flatten())
+        })
+    }
+}
+
+impl<const N: usize, const B: usize, C, L> Tree<N, B, C, L>
+where
+    C: Sized + Send,
+    L: LodVec<N>,
+{
+    /// Same as lod_update, but all new chunks are created in parallel on rayon's thread pool
+    /// before being linked into the tree. Evictions happen on the calling thread.
+    /// # Params
+    /// * `targets` the target positions to generate the maximal level of detail around, e.g. players
+    /// * `detail` the size of the region which will be filled with max level of detail
+    /// * `chunk_creator` function to create a new chunk from a given position, called from many threads
+    /// * `evict_callback` function to dispose of unneeded chunks (can move them into cache or whatever)
+    pub fn lod_update_par<V, W>(
+        &mut self,
+        targets: &[L],
+        detail: u32,
+        chunk_creator: V,
+        evict_callback: W,
+    ) where
+        V: Fn(L) -> C + Sync,
+        W: FnMut(L, C),
+    {
+        let plan = self.prepare_update(targets, detail);
+        self.do_update_par(plan, chunk_creator, evict_callback);
+    }
+
+    /// Same as lod_update_targets, but all new chunks are created in parallel (see lod_update_par).
+    pub fn lod_update_targets_par<V, W>(
+        &mut self,
+        targets: &[LodTarget<N, L>],
+        chunk_creator: V,
+        evict_callback: W,
+    ) where
+        V: Fn(L) -> C + Sync,
+        W: FnMut(L, C),
+    {
+        let plan = self.prepare_update_targets(targets);
+        self.do_update_par(plan, chunk_creator, evict_callback);
+    }
+
+    /// Generates chunks for the plan in parallel, then applies it.
+    fn do_update_par<V, W>(
+        &mut self,
+        plan: LodUpdatePlan<N, L>,
+        chunk_creator: V,
+        evict_callback: W,
+    ) where
+        V: Fn(L) -> C + Sync,
+        W: FnMut(L, C),
+    {
+        let chunks: Vec<C> = plan
+            .to_create
+            .par_iter()
+            .map(|&p| chunk_creator(p))
+            .collect();
+        self.do_update(plan, chunks, evict_callback)
+            .expect("Plan was just made for this tree");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lod_update_par() {
+        let mut tree = OctTree::<OctVec, OctVec>::new();
+        let mut tree2 = OctTree::<OctVec, OctVec>::new();
+        for targets in [
+            [OctVec::build(3, 3, 3, 4), OctVec::build(12, 1, 9, 4)],
+            [OctVec::build(4, 3, 3, 4), OctVec::build(6, 0, 4, 3)],
+        ] {
+            let mut evicted = 0;
+            tree.lod_update(&targets, 1, |p| p, |_, _| evicted += 1);
+            let mut evicted2 = 0;
+            tree2.lod_update_par(
+                &targets,
+                1,
+                |p| p,
+                |p, c| {
+                    assert_eq!(p, c);
+                    evicted2 += 1
+                },
+            );
+            assert_eq!(evicted, evicted2);
+            assert_eq!(tree.get_num_chunks(), tree2.get_num_chunks());
+            for (_, c) in tree2.iter_chunks() {
+                assert_eq!(c.chunk, c.position());
+                assert!(tree.get_chunk_by_position(c.position()).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn par_iter_chunks() {
+        let mut tree = OctTree::<usize, OctVec>::new();
+        let min = OctVec::build(0, 0, 0, 4);
+        let max = OctVec::build(15, 15, 15, 4);
+        tree.insert_many(iter_all_positions_in_bounds(min, max), |p| p.depth as usize);
+        tree.pop_chunk_by_position(OctVec::build(1, 1, 1, 2));
+
+        let total: usize = tree.iter_chunks().map(|(_, c)| c.chunk).sum();
+        assert_eq!(
+            tree.par_iter_chunks().map(|(_, c)| c.chunk).sum::<usize>(),
+            total
+        );
+        tree.par_iter_chunks_mut().for_each(|(_, c)| c.chunk += 1);
+        assert_eq!(
+            tree.par_iter_chunks().map(|(_, c)| c.chunk).sum::<usize>(),
+            total + tree.get_num_chunks()
+        );
+
+        for (min, max) in [
+            (OctVec::build(2, 3, 1, 4), OctVec::build(12, 5, 15, 4)),
+            (OctVec::build(0, 0, 0, 1), OctVec::build(1, 1, 0, 1)),
+            (OctVec::build(3, 3, 3, 3), OctVec::build(4, 4, 4, 3)),
+        ] {
+            let mut expected: Vec<_> = tree
+                .iter_chunks_in_aabb(min, max)
+                .map(|(p, _)| p.idx)
+                .collect();
+            let mut found: Vec<_> = tree
+                .par_iter_chunks_in_aabb(min, max)
+                .map(|(p, c)| {
+                    assert_eq!(tree.get_chunk(p.idx).position(), p.pos);
+                    assert_eq!(*c, p.pos.depth as usize + 1);
+                    p.idx
+                })
+                .collect();
+            expected.sort();
+            found.sort();
+            assert_eq!(found, expected);
+
+            tree.par_iter_chunks_in_aabb_mut(min, max)
+                .for_each(|(_, c)| *c += 100);
+            // every chunk in the box got exactly one increment, and nothing else changed
+            for idx in expected {
+                let c = tree.get_chunk_mut(idx);
+                assert!(c.chunk > 100);
+                c.chunk -= 100;
+            }
+            assert_eq!(
+                tree.iter_chunks().map(|(_, c)| c.chunk).sum::<usize>(),
+                total + tree.get_num_chunks()
+            );
+        }
+    }
+
+    #[test]
+    fn par_iter_chunks_in_aabb_mut() {
+        // big enough to be split between several threads, unless running under Miri
+        let depth = if cfg!(miri) { 2 } else { 5 };
+        let side = (1u8 << depth) - 1;
+        let mut tree = OctTree::<usize, OctVec>::new();
+        let min = OctVec::build(0, 0, 0, depth);
+        let max = OctVec::build(side, side, side, depth);
+        tree.insert_many(iter_all_positions_in_bounds(min, max), |_| 0);
+
+        // force a real thread pool even on single-core machines
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            tree.par_iter_chunks_in_aabb_mut(min, max)
+                .for_each(|(p, c)| *c += p.pos.depth as usize)
+        });
+        for (_, c) in tree.iter_chunks() {
+            assert_eq!(c.chunk, c.position().depth as usize);
+        }
+    }
+}
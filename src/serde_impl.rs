@@ -0,0 +1,321 @@
+/* Generic tree structures for storage of spatial data.
+ * Copyright (C) 2023  Alexander Pyattaev
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Serde support for coordinates, chunk containers and trees (enabled with "serde" feature).
+//!
+//! Trees are stored as a plain sequence of (position, chunk) pairs, node structure is never
+//! written out. On load the tree is rebuilt from these pairs, so node and chunk slabs come out
+//! valid and compact no matter what the input was.
+
+use crate::coords::*;
+use crate::snapshot::MAX_PREALLOC;
+use crate::tree::*;
+use crate::util_funcs::*;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Position array, serialized as a tuple since serde has no impls for arrays of arbitrary length.
+struct PosArray<const N: usize, DT>([DT; N]);
+
+impl<const N: usize, DT> Serialize for PosArray<N, DT>
+where
+    DT: ReasonableIntegerLike + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(N)?;
+        for e in self.0.iter() {
+            tup.serialize_element(e)?;
+        }
+        tup.end()
+    }
+}
+
+impl<'de, const N: usize, DT> Deserialize<'de> for PosArray<N, DT>
+where
+    DT: ReasonableIntegerLike + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PosVisitor<const N: usize, DT>(PhantomData<DT>);
+
+        impl<'de, const N: usize, DT> Visitor<'de> for PosVisitor<N, DT>
+        where
+            DT: ReasonableIntegerLike + Deserialize<'de>,
+        {
+            type Value = PosArray<N, DT>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an array of {N} coordinates")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut pos = [DT::default(); N];
+                for (i, p) in pos.iter_mut().enumerate() {
+                    *p = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+                }
+                Ok(PosArray(pos))
+            }
+        }
+
+        deserializer.deserialize_tuple(N, PosVisitor(PhantomData))
+    }
+}
+
+/// Wire representation of CoordVec
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename = "CoordVec")]
+#[serde(bound(
+    serialize = "DT: ReasonableIntegerLike + Serialize",
+    deserialize = "DT: ReasonableIntegerLike + Deserialize<'de>"
+))]
+struct CoordVecRepr<const N: usize, DT> {
+    pos: PosArray<N, DT>,
+    depth: u8,
+}
+
+impl<const N: usize, DT> Serialize for CoordVec<N, DT>
+where
+    DT: ReasonableIntegerLike + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        CoordVecRepr {
+            pos: PosArray(self.pos),
+            depth: self.depth,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, const N: usize, DT> Deserialize<'de> for CoordVec<N, DT>
+where
+    DT: ReasonableIntegerLike + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let CoordVecRepr { pos, depth } = CoordVecRepr::<N, DT>::deserialize(deserializer)?;
+        // do not trust the input, CoordVec::new only checks this in debug builds
+        if depth > MAX_DEPTH {
+            return Err(de::Error::custom(format_args!(
+                "depth {depth} exceeds maximal depth {MAX_DEPTH}"
+            )));
+        }
+        if pos.0.iter().any(|e| e.tousize() >= (1 << depth)) {
+            return Err(de::Error::custom(format_args!(
+                "position {:?} out of range for depth {depth}",
+                pos.0
+            )));
+        }
+        Ok(CoordVec::new(pos.0, depth))
+    }
+}
+
+/// Wire representation of ChunkContainer (serialization side)
+#[derive(serde::Serialize)]
+#[serde(rename = "ChunkContainer")]
+struct ChunkRepr<'a, L, C> {
+    position: L,
+    chunk: &'a C,
+}
+
+/// Wire representation of ChunkContainer (deserialization side)
+#[derive(serde::Deserialize)]
+#[serde(rename = "ChunkContainer")]
+struct ChunkReprOwned<L, C> {
+    position: L,
+    chunk: C,
+}
+
+/// Only position and the chunk itself are stored.
+impl<const N: usize, C, L> Serialize for ChunkContainer<N, C, L>
+where
+    C: Sized + Serialize,
+    L: LodVec<N> + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ChunkRepr {
+            position: self.position,
+            chunk: &self.chunk,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A deserialized container is not attached to any tree, use it to access position and chunk.
+impl<'de, const N: usize, C, L> Deserialize<'de> for ChunkContainer<N, C, L>
+where
+    C: Sized + Deserialize<'de>,
+    L: LodVec<N> + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ChunkReprOwned { position, chunk } = ChunkReprOwned::deserialize(deserializer)?;
+        Ok(ChunkContainer {
+            chunk,
+            position,
+            node_idx: 0,
+            child_idx: 0,
+        })
+    }
+}
+
+/// Tree is stored as sequence of its chunks, in the order they are stored in memory.
+impl<const N: usize, const B: usize, C, L> Serialize for Tree<N, B, C, L>
+where
+    C: Sized + Serialize,
+    L: LodVec<N> + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter_chunks().map(|(_, c)| c))
+    }
+}
+
+/// Tree is rebuilt by inserting chunks one by one, so node structure is always valid.
+/// Duplicate positions and root position are rejected.
+impl<'de, const N: usize, const B: usize, C, L> Deserialize<'de> for Tree<N, B, C, L>
+where
+    C: Sized + Deserialize<'de>,
+    L: LodVec<N> + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TreeVisitor<const N: usize, const B: usize, C, L>(PhantomData<(C, L)>);
+
+        impl<'de, const N: usize, const B: usize, C, L> Visitor<'de> for TreeVisitor<N, B, C, L>
+        where
+            C: Sized + Deserialize<'de>,
+            L: LodVec<N> + Deserialize<'de>,
+        {
+            type Value = Tree<N, B, C, L>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a sequence of chunks with their positions")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                // size hint comes from the input, so it can not be trusted either
+                let num_chunks = seq.size_hint().unwrap_or(0).clamp(1, MAX_PREALLOC);
+                let mut tree = Tree::with_capacity_unsafe(1, num_chunks);
+                while let Some(ChunkReprOwned { position, chunk }) =
+                    seq.next_element::<ChunkReprOwned<L, C>>()?
+                {
+                    if position == L::root() {
+                        return Err(de::Error::custom("root is not a valid chunk position"));
+                    }
+                    match tree.entry(position) {
+                        Entry::Vacant(e) => {
+                            e.insert(chunk);
+                        }
+                        Entry::Occupied(_) => {
+                            return Err(de::Error::custom(format_args!(
+                                "duplicate chunk at position {position:?}"
+                            )));
+                        }
+                    }
+                }
+                Ok(tree)
+            }
+        }
+
+        deserializer.deserialize_seq(TreeVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iter::*;
+
+    #[test]
+    fn coords_roundtrip() {
+        let v = OctVec::<u16>::build(3, 200, 7, 9);
+        let s = serde_json::to_string(&v).unwrap();
+        assert_eq!(s, r#"{"pos":[3,200,7],"depth":9}"#);
+        let v2: OctVec<u16> = serde_json::from_str(&s).unwrap();
+        assert_eq!(v, v2);
+
+        // out of range positions and wrong dimensions are rejected
+        assert!(serde_json::from_str::<QuadVec>(r#"{"pos":[4,1],"depth":2}"#).is_err());
+        assert!(serde_json::from_str::<QuadVec>(r#"{"pos":[1,1,1],"depth":2}"#).is_err());
+        assert!(serde_json::from_str::<QuadVec>(r#"{"pos":[1],"depth":2}"#).is_err());
+        assert!(serde_json::from_str::<QuadVec>(r#"{"pos":[0,0],"depth":61}"#).is_err());
+    }
+
+    #[test]
+    fn tree_roundtrip() {
+        let mut tree = OctTree::<u32, OctVec>::new();
+        let min = OctVec::build(0, 0, 0, 3);
+        let max = OctVec::build(5, 6, 7, 3);
+        tree.insert_many(iter_all_positions_in_bounds(min, max), |p| {
+            p.pos.iter().map(|e| *e as u32).sum::<u32>() + p.depth as u32 * 100
+        });
+        // make some holes to make sure they do not end up in the copy
+        tree.pop_chunk_by_position(OctVec::build(1, 1, 1, 3));
+        tree.pop_chunk_by_position(OctVec::build(0, 0, 0, 1));
+
+        let s = serde_json::to_string(&tree).unwrap();
+        let tree2: OctTree<u32, OctVec> = serde_json::from_str(&s).unwrap();
+
+        assert_eq!(tree.get_num_chunks(), tree2.get_num_chunks());
+        for (_, c) in tree.iter_chunks() {
+            assert_eq!(tree2.get_chunk_by_position(c.position()), Some(&c.chunk));
+        }
+        // new tree has no holes in chunk storage
+        assert!(tree2
+            .iter_chunks()
+            .map(|(i, _)| i)
+            .eq(0..tree2.get_num_chunks()));
+        for (i, c) in tree2.iter_chunks() {
+            let node = &tree2.nodes[c.node_idx as usize];
+            assert_eq!(node.chunk[c.child_idx as usize].get(), Some(i));
+        }
+    }
+
+    #[test]
+    fn tree_rejects_bad_input() {
+        let dup = r#"[{"position":{"pos":[1,1],"depth":1},"chunk":1},
+                      {"position":{"pos":[1,1],"depth":1},"chunk":2}]"#;
+        assert!(serde_json::from_str::<QuadTree<u32, QuadVec>>(dup).is_err());
+        let root = r#"[{"position":{"pos":[0,0],"depth":0},"chunk":1}]"#;
+        assert!(serde_json::from_str::<QuadTree<u32, QuadVec>>(root).is_err());
+        let empty: QuadTree<u32, QuadVec> = serde_json::from_str("[]").unwrap();
+        assert_eq!(empty.get_num_chunks(), 0);
+    }
+
+    /// Sequence that claims to be huge (like a corrupt length prefix would) but is cut short.
+    struct BogusLength;
+
+    impl<'de> SeqAccess<'de> for BogusLength {
+        type Error = de::value::Error;
+
+        fn next_element_seed<T: de::DeserializeSeed<'de>>(
+            &mut self,
+            _seed: T,
+        ) -> Result<Option<T::Value>, Self::Error> {
+            Err(de::Error::custom("unexpected end of input"))
+        }
+
+        fn size_hint(&self) -> Option<usize> {
+            Some(usize::MAX)
+        }
+    }
+
+    #[test]
+    fn tree_rejects_bogus_length() {
+        let de = de::value::SeqAccessDeserializer::new(BogusLength);
+        assert!(OctTree::<u32, OctVec>::deserialize(de).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+/* Generic tree structures for storage of spatial data.
+ * Copyright (C) 2023  Alexander Pyattaev
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Compact versioned binary snapshots of trees.
+//!
+//! Layout of a snapshot (all integers are little endian):
+//!  * header: magic `SPTR`, format version (u16), N (u8), B (u16), coordinate width in bits (u8),
+//!    number of nodes (u64) and number of chunks (u64)
+//!  * node records in breadth-first order (same as defragment_nodes produces), starting with root.
+//!    Each record is a bitmask of present children, a bitmask of present chunks (each B bits
+//!    rounded up to whole bytes), followed by payloads of the node's chunks in child slot order.
+//!
+//! Positions are never stored, they are recovered from the node topology.
+//! Chunk payloads are written and read by user-supplied functions.
+
+use crate::coords::*;
+use crate::tree::*;
+use crate::util_funcs::*;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+
+/// Magic bytes at the start of every snapshot
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"SPTR";
+/// Current version of the snapshot format
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Max number of nodes and chunks to preallocate based on counts from untrusted input
+/// (snapshot headers, serde size hints), which may be bogus.
+/// Storage grows past this as records are actually read.
+pub(crate) const MAX_PREALLOC: usize = 1 << 16;
+
+/// Errors that may occur while reading or writing snapshots
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// Underlying reader/writer (or chunk encoder/decoder) failed
+    Io(std::io::Error),
+    /// Data does not start with the snapshot magic
+    BadMagic,
+    /// Snapshot was written in a format version this crate can not read
+    UnsupportedVersion(u16),
+    /// Snapshot was written for a tree with different N and B
+    DimensionMismatch {
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+    /// Snapshot was written for a tree with different coordinate integer width (in bits)
+    CoordWidthMismatch { expected: u8, found: u8 },
+    /// Node topology in the snapshot is not valid
+    Corrupt(&'static str),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot I/O error: {e}"),
+            SnapshotError::BadMagic => write!(f, "not a tree snapshot (bad magic)"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {v}")
+            }
+            SnapshotError::DimensionMismatch { expected, found } => write!(
+                f,
+                "snapshot is for a tree with N={}, B={}, expected N={}, B={}",
+                found.0, found.1, expected.0, expected.1
+            ),
+            SnapshotError::CoordWidthMismatch { expected, found } => write!(
+                f,
+                "snapshot uses {found} bit coordinates, expected {expected} bit"
+            ),
+            SnapshotError::Corrupt(msg) => write!(f, "corrupt snapshot: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SnapshotError {
+    #[inline]
+    fn from(e: std::io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// Writes B bits of mask as little-endian bytes
+#[inline]
+fn write_mask<W: Write, const B: usize>(
+    writer: &mut W,
+    bits: impl Iterator<Item = bool>,
+) -> std::io::Result<()> {
+    // B bytes is always enough, and keeps this on the stack
+    let mut bytes = [0u8; B];
+    for (i, b) in bits.enumerate() {
+        bytes[i / 8] |= (b as u8) << (i % 8);
+    }
+    writer.write_all(&bytes[..B.div_ceil(8)])
+}
+
+/// Reads B bits of mask written by write_mask
+#[inline]
+fn read_mask<R: Read, const B: usize>(reader: &mut R) -> std::io::Result<[bool; B]> {
+    let mut bytes = [0u8; B];
+    reader.read_exact(&mut bytes[..B.div_ceil(8)])?;
+    let mut mask = [false; B];
+    for (i, m) in mask.iter_mut().enumerate() {
+        *m = bytes[i / 8] & (1 << (i % 8)) != 0;
+    }
+    Ok(mask)
+}
+
+impl<const N: usize, const B: usize, C, DT> Tree<N, B, C, CoordVec<N, DT>>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+{
+    /// Width of the coordinate integers in bits, as written into the snapshot header
+    const COORD_BITS: u8 = (std::mem::size_of::<DT>() * 8) as u8;
+
+    /// Writes a binary snapshot of the tree. See module documentation for format description.
+    /// # Params
+    /// * `writer` where to put the data, consider wrapping it into a BufWriter
+    /// * `encoder` function to write a chunk at a given position
+    pub fn write_snapshot<W, F>(&self, mut writer: W, mut encoder: F) -> Result<(), SnapshotError>
+    where
+        W: Write,
+        F: FnMut(&mut W, CoordVec<N, DT>, &C) -> std::io::Result<()>,
+    {
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&[N as u8])?;
+        writer.write_all(&(B as u16).to_le_bytes())?;
+        writer.write_all(&[Self::COORD_BITS])?;
+        writer.write_all(&(self.nodes.len() as u64).to_le_bytes())?;
+        writer.write_all(&(self.chunks.len() as u64).to_le_bytes())?;
+
+        // breadth-first traverse, so reader can assign node indices in the same order
+        let mut queue = VecDeque::with_capacity(B);
+        queue.push_back(TreePos {
+            idx: 0,
+            pos: CoordVec::root(),
+        });
+        let mut written_nodes = 0;
+        while let Some(addr) = queue.pop_front() {
+            let node = &self.nodes[addr.idx];
+            write_mask::<W, B>(&mut writer, node.children.iter().map(|c| c.is_some()))?;
+            write_mask::<W, B>(&mut writer, node.chunk.iter().map(|c| c.get().is_some()))?;
+            for (b, chunk_idx) in node.iter_existing_chunks() {
+                let cont = &self.chunks[chunk_idx];
+                debug_assert_eq!(cont.position, addr.pos.get_child(b));
+                encoder(&mut writer, cont.position, &cont.chunk)?;
+            }
+            for (b, child_idx) in iter_treenode_children(&node.children) {
+                queue.push_back(TreePos {
+                    idx: child_idx,
+                    pos: addr.pos.get_child(b),
+                });
+            }
+            written_nodes += 1;
+        }
+        debug_assert_eq!(written_nodes, self.nodes.len(), "Unreachable nodes in tree");
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads a tree from binary snapshot written by write_snapshot.
+    /// Rejects snapshots written for trees with different dimensions or coordinate width.
+    /// Node storage of the resulting tree is compact and laid out in breadth-first order.
+    /// # Params
+    /// * `reader` where to get the data from, consider wrapping it into a BufReader
+    /// * `decoder` function to read a chunk at a given position
+    pub fn read_snapshot<R, F>(mut reader: R, mut decoder: F) -> Result<Self, SnapshotError>
+    where
+        R: Read,
+        F: FnMut(&mut R, CoordVec<N, DT>) -> std::io::Result<C>,
+    {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut buf2 = [0u8; 2];
+        reader.read_exact(&mut buf2)?;
+        let version = u16::from_le_bytes(buf2);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let mut buf1 = [0u8; 1];
+        reader.read_exact(&mut buf1)?;
+        let n = buf1[0] as usize;
+        reader.read_exact(&mut buf2)?;
+        let b = u16::from_le_bytes(buf2) as usize;
+        if (n, b) != (N, B) {
+            return Err(SnapshotError::DimensionMismatch {
+                expected: (N, B),
+                found: (n, b),
+            });
+        }
+        reader.read_exact(&mut buf1)?;
+        if buf1[0] != Self::COORD_BITS {
+            return Err(SnapshotError::CoordWidthMismatch {
+                expected: Self::COORD_BITS,
+                found: buf1[0],
+            });
+        }
+        let mut buf8 = [0u8; 8];
+        reader.read_exact(&mut buf8)?;
+        let num_nodes = u64::from_le_bytes(buf8);
+        reader.read_exact(&mut buf8)?;
+        let num_chunks = u64::from_le_bytes(buf8);
+        // check the counts before converting, so nothing gets truncated on 32 bit targets
+        if num_nodes == 0 || num_nodes > u32::MAX as u64 || num_chunks > i32::MAX as u64 {
+            return Err(SnapshotError::Corrupt("node or chunk count out of range"));
+        }
+        let out_of_range = |_| SnapshotError::Corrupt("node or chunk count out of range");
+        let num_nodes = usize::try_from(num_nodes).map_err(out_of_range)?;
+        let num_chunks = usize::try_from(num_chunks).map_err(out_of_range)?;
+
+        // deepest position that can hold a chunk with these coordinates
+        let max_depth = (MAX_DEPTH - 1).min(Self::COORD_BITS);
+
+        let mut tree = Self::with_capacity_unsafe(
+            num_nodes.min(MAX_PREALLOC),
+            num_chunks.clamp(1, MAX_PREALLOC),
+        );
+        // positions of nodes, in the order they were created (which is also their index)
+        let mut positions = Vec::with_capacity(num_nodes.min(MAX_PREALLOC));
+        positions.push(CoordVec::root());
+
+        for n in 0..num_nodes {
+            let pos = *positions.get(n).ok_or(SnapshotError::Corrupt(
+                "more nodes than reachable from root",
+            ))?;
+            let children = read_mask::<R, B>(&mut reader)?;
+            let chunks = read_mask::<R, B>(&mut reader)?;
+            if pos.depth >= max_depth && children.iter().chain(chunks.iter()).any(|x| *x) {
+                return Err(SnapshotError::Corrupt("tree too deep for coordinate type"));
+            }
+            for b in (0..B).filter(|b| chunks[*b]) {
+                if tree.chunks.len() == num_chunks {
+                    return Err(SnapshotError::Corrupt("more chunks than declared"));
+                }
+                let child_pos = pos.get_child(b);
+                let chunk = decoder(&mut reader, child_pos)?;
+                let chunk_idx = tree.chunks.insert(ChunkContainer {
+                    chunk,
+                    position: child_pos,
+                    node_idx: n as u32,
+                    child_idx: b as u8,
+                });
+                tree.nodes[n].chunk[b] = ChunkPtr::from(Some(chunk_idx));
+            }
+            for b in (0..B).filter(|b| children[*b]) {
+                if tree.nodes.len() == num_nodes {
+                    return Err(SnapshotError::Corrupt("more nodes than declared"));
+                }
+                let new_idx = tree.nodes.insert(TreeNode::new());
+                debug_assert_eq!(new_idx, positions.len());
+                positions.push(pos.get_child(b));
+                tree.nodes[n].children[b] = NonZeroU32::new(new_idx as u32);
+            }
+        }
+        if tree.chunks.len() != num_chunks {
+            return Err(SnapshotError::Corrupt("fewer chunks than declared"));
+        }
+        if tree.nodes.len() != num_nodes {
+            return Err(SnapshotError::Corrupt("fewer nodes than declared"));
+        }
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iter::*;
+
+    fn encode(w: &mut Vec<u8>, _: OctVec, c: &u32) -> std::io::Result<()> {
+        w.write_all(&c.to_le_bytes())
+    }
+
+    fn decode(r: &mut &[u8], _: OctVec) -> std::io::Result<u32> {
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn make_tree() -> OctTree<u32, OctVec> {
+        let mut tree = OctTree::<u32, OctVec>::new();
+        let min = OctVec::build(1, 0, 0, 3);
+        let max = OctVec::build(5, 6, 7, 3);
+        tree.insert_many(iter_all_positions_in_bounds(min, max), |p| {
+            p.pos.iter().map(|e| *e as u32).sum::<u32>() + p.depth as u32 * 100
+        });
+        tree.insert(OctVec::build(255, 0, 3, 8), |_| 42);
+        tree.pop_chunk_by_position(OctVec::build(1, 1, 1, 3));
+        tree
+    }
+
+    #[test]
+    fn roundtrip() {
+        let tree = make_tree();
+        let mut data = Vec::new();
+        tree.write_snapshot(&mut data, |w, p, c| encode(w, p, c))
+            .unwrap();
+        let tree2 = OctTree::<u32, OctVec>::read_snapshot(data.as_slice(), decode).unwrap();
+        assert_eq!(tree.get_num_chunks(), tree2.get_num_chunks());
+        assert_eq!(tree.nodes.len(), tree2.nodes.len());
+        for (_, c) in tree.iter_chunks() {
+            assert_eq!(tree2.get_chunk_by_position(c.position()), Some(&c.chunk));
+        }
+        for (i, c) in tree2.iter_chunks() {
+            let node = &tree2.nodes[c.node_idx as usize];
+            assert_eq!(node.chunk[c.child_idx as usize].get(), Some(i));
+        }
+
+        // empty tree is fine too
+        let mut data = Vec::new();
+        OctTree::<u32, OctVec>::new()
+            .write_snapshot(&mut data, |w, p, c| encode(w, p, c))
+            .unwrap();
+        let empty = OctTree::<u32, OctVec>::read_snapshot(data.as_slice(), decode).unwrap();
+        assert_eq!(empty.get_num_chunks(), 0);
+    }
+
+    #[test]
+    fn rejects_mismatches() {
+        let tree = make_tree();
+        let mut data = Vec::new();
+        tree.write_snapshot(&mut data, |w, p, c| encode(w, p, c))
+            .unwrap();
+
+        let r = QuadTree::<u32, QuadVec>::read_snapshot(data.as_slice(), |_, _| Ok(0));
+        assert!(matches!(
+            r,
+            Err(SnapshotError::DimensionMismatch {
+                expected: (2, 4),
+                found: (3, 8)
+            })
+        ));
+
+        let r = OctTree::<u32, OctVec<u16>>::read_snapshot(data.as_slice(), |_, _| Ok(0));
+        assert!(matches!(
+            r,
+            Err(SnapshotError::CoordWidthMismatch {
+                expected: 16,
+                found: 8
+            })
+        ));
+
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        let r = OctTree::<u32, OctVec>::read_snapshot(bad.as_slice(), decode);
+        assert!(matches!(r, Err(SnapshotError::BadMagic)));
+
+        let mut bad = data.clone();
+        bad[4] = 99;
+        let r = OctTree::<u32, OctVec>::read_snapshot(bad.as_slice(), decode);
+        assert!(matches!(r, Err(SnapshotError::UnsupportedVersion(99))));
+
+        let truncated = &data[..data.len() - 3];
+        let r = OctTree::<u32, OctVec>::read_snapshot(truncated, decode);
+        assert!(matches!(r, Err(SnapshotError::Io(_))));
+
+        // claim there are more chunks than there really are
+        let mut bad = data.clone();
+        bad[18] += 1;
+        let r = OctTree::<u32, OctVec>::read_snapshot(bad.as_slice(), decode);
+        assert!(matches!(r, Err(SnapshotError::Corrupt(_))));
+
+        // huge counts in the header must not be trusted for allocation
+        let mut bad = data[..26].to_vec();
+        bad[10..18].copy_from_slice(&(u32::MAX as u64).to_le_bytes());
+        bad[18..26].copy_from_slice(&(i32::MAX as u64).to_le_bytes());
+        let r = OctTree::<u32, OctVec>::read_snapshot(bad.as_slice(), decode);
+        assert!(matches!(r, Err(SnapshotError::Io(_))));
+
+        // counts that would wrap around to small numbers on 32 bit targets
+        let mut bad = data.clone();
+        bad[10..18].copy_from_slice(&((1u64 << 32) + 1).to_le_bytes());
+        let r = OctTree::<u32, OctVec>::read_snapshot(bad.as_slice(), decode);
+        assert!(matches!(r, Err(SnapshotError::Corrupt(_))));
+        let mut bad = data.clone();
+        bad[18..26].copy_from_slice(&((1u64 << 32) + 1).to_le_bytes());
+        let r = OctTree::<u32, OctVec>::read_snapshot(bad.as_slice(), decode);
+        assert!(matches!(r, Err(SnapshotError::Corrupt(_))));
+    }
+}
@@ -17,9 +17,12 @@
 
 //! Contains the tree struct, which is used to hold all chunks
 
+use crate::cache::*;
 use crate::coords::*;
 use crate::util_funcs::*;
 use slab::Slab;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::num::NonZeroU32;
 use std::ops::ControlFlow;
@@ -46,11 +49,254 @@ pub struct Tree<const N: usize, const B: usize, C: Sized, L: LodVec<N>> {
     pub(crate) nodes: NodeStorage<B>,
     /// Temporary buffer for nodes used during rebuilds
     new_nodes: Slab<TreeNode<B>>,
+    /// Optional cache for chunks evicted during LOD updates
+    cache: Option<ChunkCache<N, C, L>>,
+    /// Balance constraint enforced by LOD updates
+    balance: LodBalance,
+    /// Identity of this tree, so update plans can not be applied to other trees
+    id: TreeId,
+    /// Counter of changes that may invalidate an LodUpdatePlan
+    generation: u64,
 }
 
-pub enum Entry<'a, C: Sized> {
-    Occupied(&'a mut C),
-    Vacant(&'a mut C),
+/// Unique identity of a tree. Clones get a new id, as plans made for the original do not apply to them.
+#[derive(Debug)]
+struct TreeId(u64);
+
+impl TreeId {
+    fn new() -> Self {
+        static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+    }
+}
+
+impl Clone for TreeId {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+/// A view into a single position of the tree, which may either be occupied by a chunk or vacant.
+/// Constructed by [`Tree::entry`], works much like the HashMap entry API.
+pub enum Entry<'a, const N: usize, const B: usize, C: Sized, L: LodVec<N>> {
+    /// There is a chunk at the position
+    Occupied(OccupiedEntry<'a, N, B, C, L>),
+    /// There is no chunk at the position
+    Vacant(VacantEntry<'a, N, B, C, L>),
+}
+
+/// A view into an occupied entry of the tree. Part of the [`Entry`] enum.
+pub struct OccupiedEntry<'a, const N: usize, const B: usize, C: Sized, L: LodVec<N>> {
+    tree: &'a mut Tree<N, B, C, L>,
+    /// index of the chunk in the chunks slab
+    chunk_idx: usize,
+}
+
+/// A view into a vacant entry of the tree. Part of the [`Entry`] enum.
+pub struct VacantEntry<'a, const N: usize, const B: usize, C: Sized, L: LodVec<N>> {
+    tree: &'a mut Tree<N, B, C, L>,
+    /// position where chunk is to be inserted
+    position: L,
+    /// deepest existing node on the way to position, insertion resumes from here
+    addr: TreePos<N, L>,
+}
+
+/// Target for lod_update_targets, i.e. a point around which the tree should be refined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LodTarget<const N: usize, L: LodVec<N>> {
+    /// position of the target, its depth is the deepest level of detail it will request
+    pub position: L,
+    /// the size of the region around the target which will be filled with max level of detail
+    pub detail: u32,
+    /// optional cap on the depth of chunks this target may request
+    pub max_depth: Option<u8>,
+}
+
+impl<const N: usize, L: LodVec<N>> LodTarget<N, L> {
+    /// create a target with a given detail and no depth cap
+    #[inline]
+    pub fn new(position: L, detail: u32) -> Self {
+        Self {
+            position,
+            detail,
+            max_depth: None,
+        }
+    }
+
+    /// limit the depth of chunks requested by this target
+    #[inline]
+    pub fn with_max_depth(mut self, max_depth: u8) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// whether this target requires a given node to be subdivided (see LodVec::can_subdivide)
+    #[inline]
+    pub fn can_subdivide(&self, node: L) -> bool {
+        self.max_depth.map_or(true, |d| node.depth() < d)
+            && self.position.can_subdivide(node, self.detail)
+    }
+}
+
+/// Balance constraint for LOD updates, see Tree::set_lod_balance.
+/// A balanced tree never has two adjacent chunks whose depths differ by more than one,
+/// which is what seam stitching between LODs usually expects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LodBalance {
+    /// no constraint, only the targets decide what gets subdivided
+    #[default]
+    None,
+    /// chunks sharing a face differ by at most one depth level
+    Faces,
+    /// chunks sharing a face, an edge or a corner differ by at most one depth level
+    Full,
+}
+
+/// Level of detail changes across the faces of a chunk, as bitmasks with one bit per face
+/// (faces are numbered as in face_offset). Useful for building skirts or transition cells between LODs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LodTransitions {
+    /// faces where neighbor chunk is bigger (lower depth)
+    pub coarser: u32,
+    /// faces where neighbor chunks are smaller (higher depth)
+    pub finer: u32,
+}
+
+impl LodTransitions {
+    /// true if neighbor across a given face is bigger
+    #[inline]
+    pub fn is_coarser(&self, face: usize) -> bool {
+        self.coarser & (1 << face) != 0
+    }
+
+    /// true if neighbors across a given face are smaller
+    #[inline]
+    pub fn is_finer(&self, face: usize) -> bool {
+        self.finer & (1 << face) != 0
+    }
+
+    /// true if all neighbors are at the same depth (or missing)
+    #[inline]
+    pub fn is_uniform(&self) -> bool {
+        self.coarser == 0 && self.finer == 0
+    }
+}
+
+/// Changes to be made to the tree by an LOD update, as computed by Tree::prepare_update.
+/// Chunks for positions in to_create can be made in any way (e.g. in parallel), and then
+/// handed to Tree::do_update in the same order.
+#[derive(Clone, Debug)]
+pub struct LodUpdatePlan<const N: usize, L: LodVec<N>> {
+    /// positions where new chunks are needed, in order expected by do_update
+    pub to_create: Vec<L>,
+    /// positions where chunks will be taken from the tree's cache instead of being created
+    pub from_cache: Vec<L>,
+    /// positions of chunks that will be evicted
+    pub to_evict: Vec<L>,
+    /// targets the plan was made for
+    targets: Vec<LodTarget<N, L>>,
+    /// nodes to subdivide, if the plan was made for a balanced tree
+    balanced: Option<HashSet<L>>,
+    /// id of the tree the plan was made for
+    tree_id: u64,
+    /// generation of the tree the plan was made for
+    generation: u64,
+}
+
+/// Reasons for Tree::do_update to reject an update plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LodUpdateError {
+    /// Plan was made for a different tree (or the tree this one was cloned from)
+    WrongTree,
+    /// Tree (or its cache) was modified after the plan was made
+    StalePlan,
+    /// Number of chunks given does not match plan.to_create
+    ChunkCountMismatch { expected: usize, found: usize },
+}
+
+impl std::fmt::Display for LodUpdateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LodUpdateError::WrongTree => write!(f, "update plan was made for a different tree"),
+            LodUpdateError::StalePlan => {
+                write!(f, "tree was modified after the update plan was made")
+            }
+            LodUpdateError::ChunkCountMismatch { expected, found } => write!(
+                f,
+                "update plan needs {expected} new chunks, but {found} were given"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LodUpdateError {}
+
+impl<const N: usize, L: LodVec<N>> LodUpdatePlan<N, L> {
+    /// true if the update would not create or evict any chunks
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.to_create.is_empty() && self.from_cache.is_empty() && self.to_evict.is_empty()
+    }
+
+    /// whether the plan wants a given node subdivided
+    #[inline]
+    fn subdivides(&self, node: L) -> bool {
+        match &self.balanced {
+            Some(set) => set.contains(&node),
+            None => self.targets.iter().any(|t| t.can_subdivide(node)),
+        }
+    }
+}
+
+/// Collects all nodes subdivided by the predicate, then adds the subdivisions
+/// needed to keep adjacent leaves within one depth level of each other.
+fn balanced_subdivisions<const N: usize, L, S>(mut subdivide: S, balance: LodBalance) -> HashSet<L>
+where
+    L: LodVec<N>,
+    S: FnMut(L) -> bool,
+{
+    let mut set = HashSet::new();
+    // subdivided nodes, grouped by depth
+    let mut levels: Vec<Vec<L>> = Vec::new();
+    let mut stack = vec![L::root()];
+    while let Some(pos) = stack.pop() {
+        for b in 0..L::MAX_CHILDREN {
+            let child_pos = pos.get_child(b);
+            if subdivide(child_pos) {
+                let depth = child_pos.depth() as usize;
+                if levels.len() <= depth {
+                    levels.resize_with(depth + 1, Vec::new);
+                }
+                levels[depth].push(child_pos);
+                set.insert(child_pos);
+                stack.push(child_pos);
+            }
+        }
+    }
+    // Children of a subdivided node are leaves at depth+1 (or deeper), so every neighbor at the same depth
+    // has to exist, i.e. have a subdivided parent. Constraints only ever go up one level, so
+    // processing levels from the deepest one catches all the ripple effects in a single pass.
+    for depth in (2..levels.len()).rev() {
+        let level = std::mem::take(&mut levels[depth]);
+        for node in level {
+            let mut require = |neighbor: L| {
+                let mut p = neighbor
+                    .parent()
+                    .expect("Neighbor of a deep node has a parent");
+                // the set is closed under ancestors, so we can stop at first known node
+                while p.depth() > 0 && set.insert(p) {
+                    levels[p.depth() as usize].push(p);
+                    p = p.parent().expect("Non-root node has a parent");
+                }
+            };
+            match balance {
+                LodBalance::Faces => node.face_neighbors().for_each(&mut require),
+                _ => node.all_neighbors().for_each(&mut require),
+            }
+        }
+    }
+    set
 }
 
 impl<const N: usize, const B: usize, C, L> Tree<N, B, C, L>
@@ -77,17 +323,144 @@ where
             chunks: Slab::with_capacity(chunks_capacity),
             nodes,
             new_nodes: Slab::new(),
+            cache: None,
+            balance: LodBalance::None,
+            id: TreeId::new(),
+            generation: 0,
+        }
+    }
+
+    /// Builds a tree from chunks sorted in depth-first (Morton) order, as given by CoordVec::morton_cmp.
+    /// The input is consumed in a single pass, with nodes created in the same order as the chunks.
+    /// If a position appears several times in a row, the last chunk for it is kept.
+    ///
+    /// Capacities are only estimated: chunks from the iterator's size hint, and nodes assuming a dense tree.
+    /// Sparse input, or iterators that give no lower bound (e.g. filter), will cause reallocations.
+    /// from_unsorted counts the exact capacities, as it has all the chunks at hand anyway.
+    ///
+    /// Unsorted input still produces a correct tree, but slower and with worse memory layout.
+    /// Use from_unsorted for that.
+    pub fn from_sorted<I>(chunks: I) -> Self
+    where
+        I: IntoIterator<Item = (L, C)>,
+    {
+        let chunks = chunks.into_iter();
+        let hint = chunks.size_hint().0;
+        // a full tree with hint chunks at the bottom has (hint - 1) / (B - 1) nodes
+        Self::from_sorted_with_capacity(
+            chunks,
+            (hint.saturating_sub(1) / (B - 1)).max(1),
+            hint.max(1),
+        )
+    }
+
+    /// Counts the nodes (including root) and chunks from_sorted will make out of sorted positions.
+    fn count_sorted<I>(positions: I) -> (usize, usize)
+    where
+        I: IntoIterator<Item = L>,
+    {
+        let mut num_nodes = 1;
+        let mut num_chunks = 0;
+        let mut last = None;
+        // same walk as in from_sorted, but only tracking positions of open nodes
+        let mut stack = arrayvec::ArrayVec::<L, { MAX_DEPTH as usize }>::new();
+        stack.push(L::root());
+        for pos in positions {
+            if last == Some(pos) {
+                continue;
+            }
+            last = Some(pos);
+            num_chunks += 1;
+            while stack.len() > 1 && !stack[stack.len() - 1].contains_child_node(pos) {
+                stack.pop();
+            }
+            let mut node = stack[stack.len() - 1];
+            while node.depth() + 1 < pos.depth() {
+                node = node.get_child(node.get_child_index(pos));
+                stack.push(node);
+                num_nodes += 1;
+            }
+        }
+        (num_nodes, num_chunks)
+    }
+
+    /// Does the actual work for from_sorted, with given capacities.
+    fn from_sorted_with_capacity<I>(
+        chunks: I,
+        nodes_capacity: usize,
+        chunks_capacity: usize,
+    ) -> Self
+    where
+        I: Iterator<Item = (L, C)>,
+    {
+        let mut tree = Self::with_capacity_unsafe(nodes_capacity, chunks_capacity.max(1));
+
+        // nodes on the path from the root to the last placed chunk.
+        // It will be as deep as the tree, so it is kept on the stack.
+        let mut stack = arrayvec::ArrayVec::<TreePos<N, L>, { MAX_DEPTH as usize }>::new();
+        stack.push(TreePos {
+            idx: 0,
+            pos: L::root(),
+        });
+        for (pos, chunk) in chunks {
+            debug_assert_ne!(pos, L::root(), "Root node is not a valid target!");
+            // close the nodes we are done with, root always stays
+            while stack.len() > 1 && !stack[stack.len() - 1].pos.contains_child_node(pos) {
+                stack.pop();
+            }
+            let mut addr = stack[stack.len() - 1];
+            // open new nodes down to the parent of pos
+            while addr.pos.depth() + 1 < pos.depth() {
+                let b = addr.pos.get_child_index(pos);
+                let idx = match tree.nodes[addr.idx].children[b] {
+                    // only happens with unsorted input
+                    Some(idx) => idx.get() as usize,
+                    None => {
+                        let idx = tree.nodes.insert(TreeNode::new());
+                        tree.nodes[addr.idx].children[b] = NonZeroU32::new(idx as u32);
+                        idx
+                    }
+                };
+                addr = TreePos {
+                    idx,
+                    pos: addr.pos.get_child(b),
+                };
+                stack.push(addr);
+            }
+            let b = addr.pos.get_child_index(pos);
+            match tree.nodes[addr.idx].chunk[b].get() {
+                Some(ci) => tree.chunks[ci].chunk = chunk,
+                None => {
+                    let ci = tree.chunks.insert(ChunkContainer {
+                        chunk,
+                        position: pos,
+                        node_idx: addr.idx as u32,
+                        child_idx: b as u8,
+                    });
+                    tree.nodes[addr.idx].chunk[b] = ChunkPtr::from(Some(ci));
+                }
+            }
         }
+        tree
+    }
+
+    /// Invalidates update plans made so far. Called by everything that adds or removes chunks,
+    /// or otherwise changes the outcome of prepare_update.
+    #[inline]
+    fn mark_modified(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
     }
     //TODO: use duplicate! on this
 
     /// Gets the node "controlling" the desired position. This means node that is one depth level above target.
-    /// Returns the index of child entry and mutable reference to the node.
+    /// Returns the index of child entry, address of the node and mutable reference to the node.
     /// If exact match is found, returns Ok variant, else Err variant with nearest match (at lower depth).
+    #[allow(clippy::type_complexity)]
     fn follow_nodes_to_position_mut(
         &mut self,
         position: L,
-    ) -> Result<(usize, &mut TreeNode<B>), (usize, &mut TreeNode<B>)> {
+    ) -> Result<(usize, TreePos<N, L>, &mut TreeNode<B>), (usize, TreePos<N, L>, &mut TreeNode<B>)>
+    {
         // start in root
         let mut addr = TreePos {
             idx: 0,
@@ -108,7 +481,7 @@ where
             let child_pos = addr.pos.get_child(child_idx);
             // if the current node is the one we are looking for, return it
             if child_pos == position {
-                return Ok((child_idx, current));
+                return Ok((child_idx, addr, current));
             }
 
             // assuming the child index points to an existing child node, follow it.
@@ -118,14 +491,14 @@ where
                     pos: addr.pos.get_child(child_idx),
                 },
                 None => {
-                    return Err((child_idx, current));
+                    return Err((child_idx, addr, current));
                 }
             };
         }
     }
 
     /// Gets the node "controlling" the desired position. This means node that is one depth level above target.
-    /// Returns the index of child entry and mutable reference to the node.
+    /// Returns the index of child entry and reference to the node.
     /// If exact match is found, returns Ok variant, else Err variant with nearest match (at lower depth).
     fn follow_nodes_to_position(
         &self,
@@ -203,21 +576,28 @@ where
     }
 
     /// get an Entry handle to modify existing or insert a new chunk.
-    /// this is WIP
+    /// The tree is only walked once, intermediate nodes are created only if a chunk is actually inserted.
     #[inline]
-    pub fn entry<V>(&mut self, _position: L, mut _chunk_creator: V) -> Entry<C>
-    where
-        V: FnMut(L) -> C,
-    {
-        todo!()
-        /*match self.follow_nodes_to_position(position){
-            Ok((idx, node))=>{(idx, node)}
-            Err((idx, node))=>{todo!()}
+    pub fn entry(&mut self, position: L) -> Entry<'_, N, B, C, L> {
+        debug_assert_ne!(position, L::root(), "Root node is not a valid target!");
+        match self.follow_nodes_to_position_mut(position) {
+            Ok((child, addr, node)) => match node.chunk[child].get() {
+                Some(chunk_idx) => Entry::Occupied(OccupiedEntry {
+                    tree: self,
+                    chunk_idx,
+                }),
+                None => Entry::Vacant(VacantEntry {
+                    tree: self,
+                    position,
+                    addr,
+                }),
+            },
+            Err((_, addr, _)) => Entry::Vacant(VacantEntry {
+                tree: self,
+                position,
+                addr,
+            }),
         }
-
-        let chunk_index = node.chunk[idx].get()?;
-        // and return the chunk
-        Some(&mut self.chunks[chunk_index].chunk)*/
     }
 
     /// get the position of a chunk, if it exists
@@ -226,6 +606,99 @@ where
         Some(self.chunks.get(index)?.position)
     }
 
+    /// Finds chunks adjacent to a given position across one of its faces (or edges/corners).
+    /// Direction is the same as offset in LodVec::neighbor, e.g. [1, 0, 0] for the +x face.
+    ///
+    /// If there are chunks at the neighboring position or deeper touching the position,
+    /// all of them are returned (several for finer neighbors, deeper ones listed after shallower).
+    /// Otherwise the deepest chunk covering the neighboring position is returned (coarser neighbor),
+    /// as long as it does not also contain the position itself.
+    /// Returns empty vector if position is at the border of the tree or nothing is adjacent.
+    pub fn find_neighbor_chunks(&self, position: L, direction: [i64; N]) -> Vec<TreePos<N, L>> {
+        let mut rv = Vec::new();
+        let target = match position.neighbor(direction) {
+            Some(t) if t != L::root() => t,
+            _ => return rv,
+        };
+        let back = direction.map(|d| -d);
+        // tests if a cell inside target is adjacent to position
+        let touches = |p: L| {
+            p.neighbor(back)
+                .is_some_and(|n| position.contains_child_node(n))
+        };
+
+        // start in root
+        let mut addr = TreePos {
+            idx: 0,
+            pos: L::root(),
+        };
+        // deepest chunk covering target, if any
+        let mut coarse = None;
+        loop {
+            let node = &self.nodes[addr.idx];
+            let child_idx = addr.pos.get_child_index(target);
+            let child_pos = addr.pos.get_child(child_idx);
+            if child_pos == target {
+                if let Some(idx) = node.chunk[child_idx].get() {
+                    rv.push(TreePos {
+                        idx,
+                        pos: child_pos,
+                    });
+                }
+                let mut to_visit = Vec::new();
+                if let Some(ni) = node.children[child_idx] {
+                    to_visit.push(TreePos {
+                        idx: ni.get() as usize,
+                        pos: child_pos,
+                    });
+                }
+                // collect everything deeper that touches the position
+                while let Some(addr) = to_visit.pop() {
+                    let node = &self.nodes[addr.idx];
+                    for b in 0..B {
+                        let child_pos = addr.pos.get_child(b);
+                        if !touches(child_pos) {
+                            continue;
+                        }
+                        if let Some(idx) = node.chunk[b].get() {
+                            rv.push(TreePos {
+                                idx,
+                                pos: child_pos,
+                            });
+                        }
+                        if let Some(ni) = node.children[b] {
+                            to_visit.push(TreePos {
+                                idx: ni.get() as usize,
+                                pos: child_pos,
+                            });
+                        }
+                    }
+                }
+                break;
+            }
+            // chunks that contain position itself are not neighbors
+            if let Some(idx) = node.chunk[child_idx].get() {
+                if !child_pos.contains_child_node(position) {
+                    coarse = Some(TreePos {
+                        idx,
+                        pos: child_pos,
+                    });
+                }
+            }
+            addr = match node.children[child_idx] {
+                Some(idx) => TreePos {
+                    idx: idx.get() as usize,
+                    pos: child_pos,
+                },
+                None => break,
+            };
+        }
+        if rv.is_empty() {
+            rv.extend(coarse);
+        }
+        rv
+    }
+
     /// Inserts/replaces chunks at specified locations.
     /// This operation will create necessary intermediate nodes to meet datastructure
     /// constraints.
@@ -250,7 +723,9 @@ where
             idx: 0,
         };
 
-        let mut tgt = targets.next().expect("Expected at least one target");
+        let Some(mut tgt) = targets.next() else {
+            return;
+        };
         loop {
             debug_assert_ne!(tgt, L::root(), "Root node is not a valid target!");
             //println!("===Inserting target {tgt:?}===");
@@ -286,9 +761,11 @@ where
     }
 
     /// Removes chunk at specified position, and returns its content (if any)
+    /// Nodes are left in place, use prune_nodes to get rid of them.
     #[inline]
     pub fn pop_chunk_by_position(&mut self, pos: L) -> Option<C> {
-        let (child, node) = self.follow_nodes_to_position_mut(pos).ok()?;
+        self.mark_modified();
+        let (child, _, node) = self.follow_nodes_to_position_mut(pos).ok()?;
         let chunk_idx = node.chunk[child].take()?;
 
         let chunk_rec = self.chunks.remove(chunk_idx);
@@ -306,6 +783,46 @@ where
     where
         V: FnMut(L) -> C,
     {
+        match self.descend_inner(addr, tgt) {
+            ControlFlow::Break(child_idx) => {
+                let chunk = chunk_creator(tgt);
+                ControlFlow::Break(self.place_chunk(addr, child_idx, chunk))
+            }
+            ControlFlow::Continue(a) => ControlFlow::Continue(a),
+        }
+    }
+
+    /// Puts chunk into given child slot of node at addr, replacing whatever chunk was there.
+    /// Returns index of the chunk.
+    #[inline]
+    fn place_chunk(&mut self, addr: TreePos<N, L>, child_idx: usize, chunk: C) -> usize {
+        self.mark_modified();
+        let current_node = self.nodes.get_mut(addr.idx).expect("Node index broken!");
+        //perform actual insertion at this location
+        match current_node.chunk[child_idx].get() {
+            Some(ci) => {
+                self.chunks[ci].chunk = chunk;
+                //println!("Found target, replacing existing chunk at {}", ci.get());
+                ci
+            }
+            None => {
+                let chunk_idx = self.chunks.insert(ChunkContainer {
+                    chunk,
+                    position: addr.pos.get_child(child_idx),
+                    node_idx: addr.idx as u32,
+                    child_idx: child_idx as u8,
+                });
+                //println!("Found target, inserting chunk at new index {chunk_idx}");
+                current_node.chunk[child_idx] = ChunkPtr::from(Some(chunk_idx));
+                chunk_idx
+            }
+        }
+    }
+
+    /// Makes one step from addr towards tgt, creating node if necessary.
+    /// Returns Break with child index if addr is the node controlling tgt, else address of next node.
+    #[inline]
+    fn descend_inner(&mut self, addr: TreePos<N, L>, tgt: L) -> ControlFlow<usize, TreePos<N, L>> {
         //dbg!(addr, tgt);
         let child_idx = addr.pos.get_child_index(tgt);
         let child_pos = addr.pos.get_child(child_idx);
@@ -313,27 +830,7 @@ where
         //println!("Current node {addr:?}");
         if child_pos == tgt {
             //println!("Found child {child_pos:?}, id {child_idx:?}");
-            let chunk = chunk_creator(tgt);
-            //perform actual insertion at this location
-            let inserted = match current_node.chunk[child_idx].get() {
-                Some(ci) => {
-                    self.chunks[ci].chunk = chunk;
-                    //println!("Found target, replacing existing chunk at {}", ci.get());
-                    ci
-                }
-                None => {
-                    let chunk_idx = self.chunks.insert(ChunkContainer {
-                        chunk,
-                        position: child_pos,
-                        node_idx: addr.idx as u32,
-                        child_idx: child_idx as u8,
-                    });
-                    //println!("Found target, inserting chunk at new index {chunk_idx}");
-                    current_node.chunk[child_idx] = ChunkPtr::from(Some(chunk_idx));
-                    chunk_idx
-                }
-            };
-            return ControlFlow::Break(inserted);
+            return ControlFlow::Break(child_idx);
         }
 
         let idx = match current_node.children[child_idx] {
@@ -384,19 +881,169 @@ where
         }
     }
 
+    /// Moves all chunks of other tree into this one.
+    /// Where both trees have a chunk at the same position, conflict is called with the position,
+    /// the chunk from this tree and the chunk from other tree, and whatever it returns is kept.
+    ///
+    /// Both node hierarchies are walked in lockstep, and branches that only exist in other tree
+    /// are moved over as a whole, without looking up every chunk from the root.
+    /// The cache of other tree (if any) is dropped.
+    pub fn merge<F>(&mut self, mut other: Self, mut conflict: F)
+    where
+        F: FnMut(L, C, C) -> C,
+    {
+        self.mark_modified();
+        // nothing to merge into, just take over the storage of other tree
+        if self.chunks.is_empty() && self.nodes.len() == 1 {
+            std::mem::swap(&mut self.chunks, &mut other.chunks);
+            std::mem::swap(&mut self.nodes, &mut other.nodes);
+            return;
+        }
+        self.chunks.reserve(other.chunks.len());
+        self.nodes.reserve(other.nodes.len() - 1);
+
+        // nodes at the same position in both trees, as (index here, index in other, position)
+        let mut stack = vec![(0, 0, L::root())];
+        while let Some((idx, other_idx, pos)) = stack.pop() {
+            let other_node = std::mem::replace(&mut other.nodes[other_idx], TreeNode::new());
+            for b in 0..B {
+                if let Some(ci) = other_node.chunk[b].get() {
+                    let theirs = other.chunks.remove(ci).chunk;
+                    let chunk = match self.nodes[idx].chunk[b].take() {
+                        Some(ci) => {
+                            conflict(pos.get_child(b), self.chunks.remove(ci).chunk, theirs)
+                        }
+                        None => theirs,
+                    };
+                    self.place_chunk(TreePos { idx, pos }, b, chunk);
+                }
+
+                let Some(other_child) = other_node.children[b] else {
+                    continue;
+                };
+                let other_child = other_child.get() as usize;
+                match self.nodes[idx].children[b] {
+                    Some(child) => {
+                        stack.push((child.get() as usize, other_child, pos.get_child(b)))
+                    }
+                    None => {
+                        let new_idx = self.move_branch(&mut other, other_child);
+                        self.nodes[idx].children[b] = NonZeroU32::new(new_idx as u32);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves the node at other_idx in other tree, along with all its descendants and their chunks, into this tree.
+    /// Returns the index of the moved node, which is not yet linked to any parent.
+    /// The pointer to the moved node in its parent in other tree is left dangling, and must be cleared by the caller.
+    fn move_branch(&mut self, other: &mut Self, other_idx: usize) -> usize {
+        let top = self.nodes.insert(TreeNode::new());
+        let mut stack = vec![(top, other_idx)];
+        while let Some((idx, other_idx)) = stack.pop() {
+            let other_node = other.nodes.remove(other_idx);
+            for b in 0..B {
+                if let Some(ci) = other_node.chunk[b].get() {
+                    let mut cont = other.chunks.remove(ci);
+                    cont.node_idx = idx as u32;
+                    self.nodes[idx].chunk[b] = ChunkPtr::from(Some(self.chunks.insert(cont)));
+                }
+                if let Some(c) = other_node.children[b] {
+                    let new_idx = self.nodes.insert(TreeNode::new());
+                    self.nodes[idx].children[b] = NonZeroU32::new(new_idx as u32);
+                    stack.push((new_idx, c.get() as usize));
+                }
+            }
+        }
+        top
+    }
+
+    /// Detaches the chunk at position and everything below it into a new tree.
+    /// Positions in the new tree stay absolute, i.e. it has the same nodes leading from the root to position.
+    /// Returns an empty tree if there is nothing at position. Splitting off the root takes everything.
+    ///
+    /// Nodes left empty in this tree are kept, use prune_nodes to get rid of them.
+    pub fn split_off(&mut self, position: L) -> Self {
+        let mut rv = Self::with_capacity_unsafe(position.depth() as usize + 1, 1);
+        rv.take_position(self, position);
+        rv
+    }
+
+    /// Puts the contents of subtree (e.g. made by split_off) at position, replacing whatever was there.
+    /// Returns the replaced part of this tree, in the same format as split_off.
+    /// # Panics
+    /// If subtree has chunks outside of position.
+    pub fn graft(&mut self, position: L, mut subtree: Self) -> Self {
+        assert!(
+            subtree
+                .chunks
+                .iter()
+                .all(|(_, c)| c.position == position || position.contains_child_node(c.position)),
+            "Subtree has chunks outside of the graft position"
+        );
+        let rv = self.split_off(position);
+        self.take_position(&mut subtree, position);
+        rv
+    }
+
+    /// Moves the chunk at position and everything below it from other tree into this one,
+    /// which must have nothing at position.
+    fn take_position(&mut self, other: &mut Self, position: L) {
+        self.mark_modified();
+        other.mark_modified();
+        if position == L::root() {
+            debug_assert!(self.chunks.is_empty() && self.nodes.len() == 1);
+            std::mem::swap(&mut self.chunks, &mut other.chunks);
+            std::mem::swap(&mut self.nodes, &mut other.nodes);
+            return;
+        }
+        let Ok((child_idx, _, node)) = other.follow_nodes_to_position_mut(position) else {
+            return;
+        };
+        let chunk = node.chunk[child_idx].take();
+        let child = node.children[child_idx].take();
+        if chunk.is_none() && child.is_none() {
+            return;
+        }
+
+        let addr = self.make_path(position);
+        if let Some(ci) = chunk {
+            self.place_chunk(addr, child_idx, other.chunks.remove(ci).chunk);
+        }
+        if let Some(c) = child {
+            debug_assert!(self.nodes[addr.idx].children[child_idx].is_none());
+            let new_idx = self.move_branch(other, c.get() as usize);
+            self.nodes[addr.idx].children[child_idx] = NonZeroU32::new(new_idx as u32);
+        }
+    }
+
+    /// Creates nodes leading to position (if needed), and returns the address of its parent node.
+    fn make_path(&mut self, position: L) -> TreePos<N, L> {
+        let mut addr = TreePos {
+            idx: 0,
+            pos: L::root(),
+        };
+        while let ControlFlow::Continue(a) = self.descend_inner(addr, position) {
+            addr = a;
+        }
+        addr
+    }
+
     #[inline]
-    pub fn iter_chunks_mut(&mut self) -> slab::IterMut<ChunkContainer<N, C, L>> {
+    pub fn iter_chunks_mut(&mut self) -> slab::IterMut<'_, ChunkContainer<N, C, L>> {
         self.chunks.iter_mut()
     }
 
     #[inline]
-    pub fn iter_chunks(&mut self) -> slab::Iter<ChunkContainer<N, C, L>> {
+    pub fn iter_chunks(&self) -> slab::Iter<'_, ChunkContainer<N, C, L>> {
         self.chunks.iter()
     }
 
     /// clears the tree, removing all nodes, chunks and internal buffers
     #[inline]
     pub fn clear(&mut self) {
+        self.mark_modified();
         self.chunks.clear();
         let root = self.nodes.remove(0);
         self.nodes.clear();
@@ -422,11 +1069,148 @@ where
         });
     }
 
+    /// Defragments the chunks array and reorders it along the Morton (Z-order) curve,
+    /// so that iter_chunks visits chunks that are close in space one after another.
+    /// Order is the same as in the depth-first traverse of the tree, i.e. chunks come before chunks inside them.
+    /// Chunks are moved in place, only a temporary array of indices is allocated.
+    pub fn defragment_chunks_morton(&mut self) {
+        self.defragment_chunks();
+        // indices of chunks in the order they should end up in.
+        let mut order = Vec::with_capacity(self.chunks.len());
+        let mut stack = arrayvec::ArrayVec::<(usize, usize), { MAX_DEPTH as usize }>::new();
+        stack.push((0, 0));
+        while let Some((idx, b)) = stack.last_mut() {
+            if *b == B {
+                stack.pop();
+                continue;
+            }
+            let node = &self.nodes[*idx];
+            *b += 1;
+            order.extend(node.chunk[*b - 1].get());
+            if let Some(c) = node.children[*b - 1] {
+                stack.push((c.get() as usize, 0));
+            }
+        }
+        debug_assert_eq!(order.len(), self.chunks.len());
+
+        // apply the permutation one cycle at a time, marking visited entries as done.
+        for i in 0..order.len() {
+            let mut k = i;
+            while order[k] != k {
+                let src = order[k];
+                order[k] = k;
+                if src == i {
+                    break;
+                }
+                let (a, b) = self
+                    .chunks
+                    .get2_mut(k, src)
+                    .expect("Chunks should be compact");
+                std::mem::swap(a, b);
+                k = src;
+            }
+        }
+
+        // chunks still know where they live, so fix the nodes pointing to them
+        for (idx, chunk) in self.chunks.iter() {
+            self.nodes[chunk.node_idx as usize].chunk[chunk.child_idx as usize] =
+                ChunkPtr::from(Some(idx));
+        }
+    }
+
     /// Prunes the nodes array to delete all nodes that have no chunks.
     /// This requires nodes to be traversed in a depth-first manner, so this is somewhat slow on larger trees
     /// You only really need this if you have deleted a whole bunch of chunks and really need the nodes memory back
+    ///
+    /// Surviving nodes are not moved, so this does not allocate. Use defragment_nodes afterwards to compact node storage.
     pub fn prune_nodes(&mut self) {
-        todo!()
+        self.remove_chunks_inner(MAX_DEPTH, |_| true, |_, _| true, |_, _| {});
+    }
+
+    /// Keeps only the chunks for which keep returns true, dropping the rest.
+    /// This is done in a single pass over the nodes, and nodes that end up empty are removed as well.
+    pub fn retain<F>(&mut self, keep: F)
+    where
+        F: FnMut(L, &mut C) -> bool,
+    {
+        self.remove_chunks_inner(MAX_DEPTH, |_| true, keep, |_, _| {});
+    }
+
+    /// Walks the nodes depth-first, only entering child slots accepted by enter, and not going below max_depth.
+    /// Chunks in those slots are removed unless keep returns true, and passed to removed.
+    /// Visited nodes that end up empty are removed. Update plans are only invalidated if some chunk was removed,
+    /// dropping empty nodes does not change their outcome.
+    pub(crate) fn remove_chunks_inner<E, K, R>(
+        &mut self,
+        max_depth: u8,
+        mut enter: E,
+        mut keep: K,
+        mut removed: R,
+    ) where
+        E: FnMut(L) -> bool,
+        K: FnMut(L, &mut C) -> bool,
+        R: FnMut(L, C),
+    {
+        let num_chunks = self.chunks.len();
+        // stack of node addresses and next child slot to visit in each of them.
+        // It will be as deep as the tree, so it is kept on the stack.
+        let mut stack = arrayvec::ArrayVec::<(TreePos<N, L>, usize), { MAX_DEPTH as usize }>::new();
+        stack.push((
+            TreePos {
+                idx: 0,
+                pos: L::root(),
+            },
+            0,
+        ));
+        while let Some((addr, b)) = stack.last_mut() {
+            if *b < B {
+                let slot = *b;
+                let addr = *addr;
+                *b += 1;
+                let child_pos = addr.pos.get_child(slot);
+                if !enter(child_pos) {
+                    continue;
+                }
+                let node = &mut self.nodes[addr.idx];
+                if let Some(ci) = node.chunk[slot].get() {
+                    if !keep(child_pos, &mut self.chunks[ci].chunk) {
+                        node.chunk[slot] = ChunkPtr::None;
+                        removed(child_pos, self.chunks.remove(ci).chunk);
+                    }
+                }
+                if let Some(c) = node.children[slot] {
+                    let idx = c.get() as usize;
+                    if child_pos.depth() < max_depth {
+                        stack.push((
+                            TreePos {
+                                idx,
+                                pos: child_pos,
+                            },
+                            0,
+                        ));
+                    } else if self.nodes[idx].is_empty() {
+                        self.nodes.remove(idx);
+                        self.nodes[addr.idx].children[slot] = None;
+                    }
+                }
+                continue;
+            }
+            // all children processed, drop the node if nothing is left in it
+            let idx = addr.idx;
+            stack.pop();
+            // root node is never removed
+            let Some((parent, pb)) = stack.last() else {
+                break;
+            };
+            if self.nodes[idx].is_empty() {
+                self.nodes.remove(idx);
+                // slot we came from is one behind the next one to visit
+                self.nodes[parent.idx].children[*pb - 1] = None;
+            }
+        }
+        if self.chunks.len() != num_chunks {
+            self.mark_modified();
+        }
     }
 
     /// Defragments the nodes array to enable faster operation and prune dead leaves.
@@ -444,6 +1228,10 @@ where
         // this will produce a breadth-first traverse of original nodes laid out in new memory, which should keep
         // nearby nodes close in memory locations.
         for n in 0..num_nodes {
+            // empty nodes are not copied, so we may run out of nodes early
+            if n >= self.new_nodes.len() {
+                break;
+            }
             // clone children array to keep it safe while we mess with it
             let children = self.new_nodes[n].children;
             // now go over node's children and move them over
@@ -457,7 +1245,7 @@ where
                 // move the child into new slab
                 let new_idx = self.new_nodes.insert(old_node);
                 // ensure slab is not doing anything fishy, and actually gives us correct indices
-                debug_assert_eq!(new_idx, n + i + 1);
+                debug_assert_eq!(new_idx, self.new_nodes.len() - 1);
                 // fix our reference to that child
                 self.new_nodes[n].children[i] = Some(NonZeroU32::new(new_idx as u32).unwrap());
 
@@ -474,24 +1262,403 @@ where
         self.new_nodes.clear();
     }
 
-    /// Prepares the tree for an LOD update. This operation reorganizes the nodes and
-    /// adds chunks around specified locations (targets) while also erasing all other chunks.
-    /// A side-effect of this is that nodes are defragmented.
+    /// Finds how level of detail changes across each face of a given position, see find_neighbor_chunks.
+    /// Faces with no neighbors (e.g. at the border of the tree) are reported as uniform.
+    pub fn lod_transitions(&self, position: L) -> LodTransitions {
+        debug_assert!(2 * N <= 32, "Too many faces for the bitmask");
+        let mut rv = LodTransitions::default();
+        for face in 0..2 * N {
+            let (coarser, finer) = self.face_transition(position, face_offset(face));
+            rv.coarser |= (coarser as u32) << face;
+            rv.finer |= (finer as u32) << face;
+        }
+        rv
+    }
+
+    /// Same classification as find_neighbor_chunks does for one direction, returned as (coarser, finer).
+    /// Nothing is collected, and the search stops at the first finer chunk.
+    fn face_transition(&self, position: L, direction: [i64; N]) -> (bool, bool) {
+        let target = match position.neighbor(direction) {
+            Some(t) if t != L::root() => t,
+            _ => return (false, false),
+        };
+        let back = direction.map(|d| -d);
+        // tests if a cell inside target is adjacent to position
+        let touches = |p: L| {
+            p.neighbor(back)
+                .is_some_and(|n| position.contains_child_node(n))
+        };
+
+        // walk down to target, noting if there is a chunk covering it (but not position itself)
+        let mut addr = TreePos {
+            idx: 0,
+            pos: L::root(),
+        };
+        let mut coarse = false;
+        let (same, below) = loop {
+            let node = &self.nodes[addr.idx];
+            let child_idx = addr.pos.get_child_index(target);
+            let child_pos = addr.pos.get_child(child_idx);
+            let has_chunk = node.chunk[child_idx].get().is_some();
+            if child_pos == target {
+                break (has_chunk, node.children[child_idx]);
+            }
+            coarse |= has_chunk && !child_pos.contains_child_node(position);
+            addr = match node.children[child_idx] {
+                Some(idx) => TreePos {
+                    idx: idx.get() as usize,
+                    pos: child_pos,
+                },
+                None => return (coarse, false),
+            };
+        };
+
+        // look for any deeper chunk touching the position.
+        // Stack will be as deep as the tree, so it is kept on the stack.
+        let mut stack = arrayvec::ArrayVec::<(TreePos<N, L>, usize), { MAX_DEPTH as usize }>::new();
+        if let Some(idx) = below {
+            stack.push((
+                TreePos {
+                    idx: idx.get() as usize,
+                    pos: target,
+                },
+                0,
+            ));
+        }
+        while let Some((addr, b)) = stack.last_mut() {
+            if *b == B {
+                stack.pop();
+                continue;
+            }
+            let slot = *b;
+            let addr = *addr;
+            *b += 1;
+            let child_pos = addr.pos.get_child(slot);
+            if !touches(child_pos) {
+                continue;
+            }
+            let node = &self.nodes[addr.idx];
+            if node.chunk[slot].get().is_some() {
+                return (false, true);
+            }
+            if let Some(idx) = node.children[slot] {
+                stack.push((
+                    TreePos {
+                        idx: idx.get() as usize,
+                        pos: child_pos,
+                    },
+                    0,
+                ));
+            }
+        }
+        (coarse && !same, false)
+    }
+
+    /// Iterate over all chunks along with their level of detail transitions (see lod_transitions).
+    #[inline]
+    pub fn iter_chunks_with_transitions(
+        &self,
+    ) -> impl Iterator<Item = (usize, &ChunkContainer<N, C, L>, LodTransitions)> + '_ {
+        self.chunks
+            .iter()
+            .map(|(i, c)| (i, c, self.lod_transitions(c.position)))
+    }
+
+    /// Attaches a cache for chunks evicted by LOD updates, or detaches it if None is given.
+    /// Returns the previously attached cache, if any.
+    #[inline]
+    pub fn set_cache(&mut self, cache: Option<ChunkCache<N, C, L>>) -> Option<ChunkCache<N, C, L>> {
+        self.mark_modified();
+        std::mem::replace(&mut self.cache, cache)
+    }
+
+    /// get a reference to the attached cache, if any
+    #[inline]
+    pub fn cache(&self) -> Option<&ChunkCache<N, C, L>> {
+        self.cache.as_ref()
+    }
+
+    /// get a mutable reference to the attached cache, if any
+    #[inline]
+    pub fn cache_mut(&mut self) -> Option<&mut ChunkCache<N, C, L>> {
+        self.mark_modified();
+        self.cache.as_mut()
+    }
+
+    /// Sets the balance constraint enforced by subsequent LOD updates (lod_update, prepare_update and friends).
+    /// With anything other than LodBalance::None, the updates subdivide extra nodes as needed
+    /// so that adjacent chunks never differ by more than one depth level.
+    #[inline]
+    pub fn set_lod_balance(&mut self, balance: LodBalance) {
+        self.mark_modified();
+        self.balance = balance;
+    }
+
+    /// get the balance constraint enforced by LOD updates
+    #[inline]
+    pub fn lod_balance(&self) -> LodBalance {
+        self.balance
+    }
+
+    /// Prepares the tree for an LOD update. This operation reorganizes the nodes and
+    /// adds chunks around specified locations (targets) while also erasing all other chunks.
+    /// A side-effect of this is that nodes are defragmented.
     /// # Params
     /// * `targets` the target positions to generate the maximal level of detail around, e.g. players
     /// * `detail` the size of the region which will be filled with max level of detail
     /// * `chunk_creator` function to create a new chunk from a given position
     /// * `evict_callback` function to dispose of unneeded chunks (can move them into cache or whatever)
+    ///
+    /// If a cache is attached with set_cache, evicted chunks go there first, and chunks from the cache
+    /// are reused before calling chunk_creator. evict_callback only gets chunks that do not fit into the cache.
     pub fn lod_update<V, W>(
         &mut self,
         targets: &[L],
         detail: u32,
+        chunk_creator: V,
+        evict_callback: W,
+    ) where
+        V: FnMut(L) -> C,
+        W: FnMut(L, C),
+    {
+        self.lod_update_balanced(
+            |node| targets.iter().any(|x| x.can_subdivide(node, detail)),
+            chunk_creator,
+            evict_callback,
+        );
+    }
+
+    /// Same as lod_update, but every target carries its own detail and depth limit.
+    /// # Params
+    /// * `targets` the targets to generate detail around, e.g. players, cameras or preloading beacons
+    /// * `chunk_creator` function to create a new chunk from a given position
+    /// * `evict_callback` function to dispose of unneeded chunks (can move them into cache or whatever)
+    pub fn lod_update_targets<V, W>(
+        &mut self,
+        targets: &[LodTarget<N, L>],
+        chunk_creator: V,
+        evict_callback: W,
+    ) where
+        V: FnMut(L) -> C,
+        W: FnMut(L, C),
+    {
+        self.lod_update_balanced(
+            |node| targets.iter().any(|t| t.can_subdivide(node)),
+            chunk_creator,
+            evict_callback,
+        );
+    }
+
+    /// First half of a two-phase LOD update. Figures out which chunks lod_update would create and evict,
+    /// without touching the tree. Apply the result with do_update.
+    /// # Params
+    /// * `targets` the target positions to generate the maximal level of detail around, e.g. players
+    /// * `detail` the size of the region which will be filled with max level of detail
+    pub fn prepare_update(&self, targets: &[L], detail: u32) -> LodUpdatePlan<N, L> {
+        let targets = targets.iter().map(|&t| LodTarget::new(t, detail)).collect();
+        self.prepare_update_targets_inner(targets)
+    }
+
+    /// Same as prepare_update, but every target carries its own detail and depth limit.
+    pub fn prepare_update_targets(&self, targets: &[LodTarget<N, L>]) -> LodUpdatePlan<N, L> {
+        self.prepare_update_targets_inner(targets.to_vec())
+    }
+
+    /// Walks the tree the same way lod_update_inner does, recording changes instead of making them.
+    fn prepare_update_targets_inner(&self, targets: Vec<LodTarget<N, L>>) -> LodUpdatePlan<N, L> {
+        let mut plan = LodUpdatePlan {
+            to_create: Vec::new(),
+            from_cache: Vec::new(),
+            to_evict: Vec::new(),
+            balanced: None,
+            targets,
+            tree_id: self.id.0,
+            generation: self.generation,
+        };
+        if self.balance != LodBalance::None {
+            let targets = &plan.targets;
+            plan.balanced = Some(balanced_subdivisions(
+                |node| targets.iter().any(|t| t.can_subdivide(node)),
+                self.balance,
+            ));
+        }
+        let mut to_create = Vec::new();
+        let mut from_cache = Vec::new();
+        let mut to_evict = Vec::new();
+        // positions of nodes that will exist after the update, along with their current index (if they exist now)
+        let mut queue = std::collections::VecDeque::with_capacity(B);
+        queue.push_back((L::root(), Some(0)));
+
+        while let Some((pos, node_idx)) = queue.pop_front() {
+            let node: Option<&TreeNode<B>> = node_idx.map(|i| &self.nodes[i]);
+            for b in 0..B {
+                let child_pos = pos.get_child(b);
+                let subdivide = plan.subdivides(child_pos);
+                let chunk = node.and_then(|n| n.chunk[b].get());
+                let child = node.and_then(|n| n.children[b]).map(|c| c.get() as usize);
+
+                match (chunk, subdivide) {
+                    (Some(_), true) => to_evict.push(child_pos),
+                    (None, false) => match &self.cache {
+                        Some(cache) if cache.contains(&child_pos) => from_cache.push(child_pos),
+                        _ => to_create.push(child_pos),
+                    },
+                    _ => {}
+                }
+                match (child, subdivide) {
+                    (_, true) => queue.push_back((child_pos, child)),
+                    // whole subtree goes away
+                    (Some(child_idx), false) => {
+                        for node in traverse(&self.nodes, &self.nodes[child_idx]) {
+                            for (_, cid) in node.iter_existing_chunks() {
+                                to_evict.push(self.chunks[cid].position);
+                            }
+                        }
+                    }
+                    (None, false) => {}
+                }
+            }
+        }
+        plan.to_create = to_create;
+        plan.from_cache = from_cache;
+        plan.to_evict = to_evict;
+        plan
+    }
+
+    /// Second half of a two-phase LOD update. Applies a plan made by prepare_update.
+    /// # Params
+    /// * `plan` the plan made by prepare_update
+    /// * `chunks` new chunks, one for every position in plan.to_create, in the same order
+    /// * `evict_callback` function to dispose of unneeded chunks (can move them into cache or whatever)
+    /// # Errors
+    /// If the plan was made for another tree, if this tree (or its cache) was modified since the plan was made,
+    /// or if the number of chunks does not match plan.to_create. This is checked before any changes are made,
+    /// so the tree stays intact.
+    pub fn do_update<I, W>(
+        &mut self,
+        plan: LodUpdatePlan<N, L>,
+        chunks: I,
+        mut evict_callback: W,
+    ) -> Result<(), LodUpdateError>
+    where
+        I: IntoIterator<Item = C>,
+        W: FnMut(L, C),
+    {
+        if plan.tree_id != self.id.0 {
+            return Err(LodUpdateError::WrongTree);
+        }
+        if plan.generation != self.generation {
+            return Err(LodUpdateError::StalePlan);
+        }
+        let chunks: Vec<C> = chunks.into_iter().collect();
+        if chunks.len() != plan.to_create.len() {
+            return Err(LodUpdateError::ChunkCountMismatch {
+                expected: plan.to_create.len(),
+                found: chunks.len(),
+            });
+        }
+
+        let mut chunks = chunks.into_iter();
+        let mut expected = plan.to_create.iter();
+        let mut cache = self.cache.take();
+        debug_assert!(cache.is_some() || plan.from_cache.is_empty());
+        // take reused chunks out first, so evictions can not push them out of the cache
+        let mut reused = std::collections::HashMap::with_capacity(plan.from_cache.len());
+        if let Some(cache) = cache.as_mut() {
+            for &pos in plan.from_cache.iter() {
+                let chunk = cache
+                    .take(pos)
+                    .expect("Cache out of sync with the update plan");
+                reused.insert(pos, chunk);
+            }
+            cache.add_misses(plan.to_create.len());
+        }
+
+        self.lod_update_inner(
+            |node| plan.subdivides(node),
+            |pos| match reused.remove(&pos) {
+                Some(chunk) => chunk,
+                None => {
+                    debug_assert_eq!(expected.next(), Some(&pos));
+                    chunks
+                        .next()
+                        .expect("Tree out of sync with the update plan")
+                }
+            },
+            |pos, chunk| match cache.as_mut() {
+                Some(cache) => {
+                    if let Some((pos, chunk)) = cache.insert(pos, chunk) {
+                        evict_callback(pos, chunk);
+                    }
+                }
+                None => evict_callback(pos, chunk),
+            },
+        );
+        debug_assert!(expected.next().is_none());
+        debug_assert!(reused.is_empty());
+        self.cache = cache;
+        Ok(())
+    }
+
+    /// Same as lod_update_cached, but extra nodes are subdivided as required by the balance constraint.
+    fn lod_update_balanced<S, V, W>(&mut self, subdivide: S, chunk_creator: V, evict_callback: W)
+    where
+        S: FnMut(L) -> bool,
+        V: FnMut(L) -> C,
+        W: FnMut(L, C),
+    {
+        if self.balance == LodBalance::None {
+            return self.lod_update_cached(subdivide, chunk_creator, evict_callback);
+        }
+        let set = balanced_subdivisions(subdivide, self.balance);
+        self.lod_update_cached(|node| set.contains(&node), chunk_creator, evict_callback);
+    }
+
+    /// Same as lod_update_inner, but evicted chunks go into the cache (if there is one),
+    /// and chunks are taken from the cache before calling chunk_creator.
+    /// Chunks that do not fit into the cache are passed on to evict_callback.
+    fn lod_update_cached<S, V, W>(
+        &mut self,
+        subdivide: S,
+        mut chunk_creator: V,
+        mut evict_callback: W,
+    ) where
+        S: FnMut(L) -> bool,
+        V: FnMut(L) -> C,
+        W: FnMut(L, C),
+    {
+        let Some(cache) = self.cache.take() else {
+            return self.lod_update_inner(subdivide, chunk_creator, evict_callback);
+        };
+        let cache = RefCell::new(cache);
+        self.lod_update_inner(
+            subdivide,
+            |pos| {
+                let cached = cache.borrow_mut().take(pos);
+                cached.unwrap_or_else(|| chunk_creator(pos))
+            },
+            |pos, chunk| {
+                let overflow = cache.borrow_mut().insert(pos, chunk);
+                if let Some((pos, chunk)) = overflow {
+                    evict_callback(pos, chunk);
+                }
+            },
+        );
+        self.cache = Some(cache.into_inner());
+    }
+
+    /// Rebuilds the tree so that exactly the nodes for which subdivide returns true are subdivided.
+    fn lod_update_inner<S, V, W>(
+        &mut self,
+        mut subdivide: S,
         mut chunk_creator: V,
         mut evict_callback: W,
     ) where
+        S: FnMut(L) -> bool,
         V: FnMut(L) -> C,
         W: FnMut(L, C),
     {
+        self.mark_modified();
         let num_nodes = self.nodes.len();
         // allocate room for new nodes (assuming it is about same amount as before update)
         // better to overallocate here than to allocate twice.
@@ -519,7 +1686,7 @@ where
                 // figure out position of child node
                 let child_pos = pos.get_child(b);
                 // figure if any of the targets needs it subdivided
-                let subdivide = targets.iter().any(|x| x.can_subdivide(child_pos, detail));
+                let subdivide = subdivide(child_pos);
                 //println!("{child_pos:?}, {subdivide:?}");
 
                 // if child is subdivided we do not want a chunk there,
@@ -603,6 +1770,180 @@ where
     }
 }
 
+impl<const N: usize, const B: usize, C, DT> Tree<N, B, C, CoordVec<N, DT>>
+where
+    C: Sized,
+    DT: ReasonableIntegerLike,
+{
+    /// Same as from_sorted, but sorts the chunks first.
+    pub fn from_unsorted<I>(chunks: I) -> Self
+    where
+        I: IntoIterator<Item = (CoordVec<N, DT>, C)>,
+    {
+        let mut chunks: Vec<(CoordVec<N, DT>, C)> = chunks.into_iter().collect();
+        // stable sort, so the last chunk for a position still wins
+        chunks.sort_by(|a, b| a.0.morton_cmp(&b.0));
+        let (num_nodes, num_chunks) = Self::count_sorted(chunks.iter().map(|c| c.0));
+        Self::from_sorted_with_capacity(chunks.into_iter(), num_nodes, num_chunks)
+    }
+}
+
+impl<'a, const N: usize, const B: usize, C, L> Entry<'a, N, B, C, L>
+where
+    C: Sized,
+    L: LodVec<N>,
+{
+    /// Returns position of this entry
+    #[inline]
+    pub fn key(&self) -> L {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+
+    /// Ensures a chunk is in the entry by inserting the default if empty.
+    /// Returns a mutable reference to the chunk.
+    #[inline]
+    pub fn or_insert(self, default: C) -> &'a mut C {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default),
+        }
+    }
+
+    /// Ensures a chunk is in the entry by inserting the result of default function if empty.
+    /// Returns a mutable reference to the chunk.
+    #[inline]
+    pub fn or_insert_with<F: FnOnce() -> C>(self, default: F) -> &'a mut C {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default()),
+        }
+    }
+
+    /// Same as or_insert_with, but the function gets the position of the entry.
+    #[inline]
+    pub fn or_insert_with_key<F: FnOnce(L) -> C>(self, default: F) -> &'a mut C {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let value = default(e.key());
+                e.insert(value)
+            }
+        }
+    }
+
+    /// Provides in-place mutable access to an occupied entry before any potential inserts.
+    #[inline]
+    pub fn and_modify<F: FnOnce(&mut C)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(e) = &mut self {
+            f(e.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, const N: usize, const B: usize, C, L> Entry<'a, N, B, C, L>
+where
+    C: Sized + Default,
+    L: LodVec<N>,
+{
+    /// Ensures a chunk is in the entry by inserting C::default() if empty.
+    /// Returns a mutable reference to the chunk.
+    #[inline]
+    pub fn or_default(self) -> &'a mut C {
+        self.or_insert_with(C::default)
+    }
+}
+
+impl<'a, const N: usize, const B: usize, C, L> OccupiedEntry<'a, N, B, C, L>
+where
+    C: Sized,
+    L: LodVec<N>,
+{
+    /// Returns position of this entry
+    #[inline]
+    pub fn key(&self) -> L {
+        self.tree.chunks[self.chunk_idx].position
+    }
+
+    /// Returns index of the chunk in the tree
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.chunk_idx
+    }
+
+    /// Gets a reference to the chunk in the entry
+    #[inline]
+    pub fn get(&self) -> &C {
+        &self.tree.chunks[self.chunk_idx].chunk
+    }
+
+    /// Gets a mutable reference to the chunk in the entry
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.tree.chunks[self.chunk_idx].chunk
+    }
+
+    /// Converts the entry into a mutable reference to the chunk with lifetime of the tree
+    #[inline]
+    pub fn into_mut(self) -> &'a mut C {
+        &mut self.tree.chunks[self.chunk_idx].chunk
+    }
+
+    /// Sets the chunk of the entry, and returns the old one
+    #[inline]
+    pub fn insert(&mut self, chunk: C) -> C {
+        std::mem::replace(self.get_mut(), chunk)
+    }
+
+    /// Takes the chunk out of the tree, and returns it.
+    /// Nodes are left in place, use prune_nodes to get rid of them.
+    #[inline]
+    pub fn remove(self) -> C {
+        self.remove_entry().1
+    }
+
+    /// Takes the chunk out of the tree, and returns it along with its position.
+    #[inline]
+    pub fn remove_entry(self) -> (L, C) {
+        self.tree.mark_modified();
+        let cont = self.tree.chunks.remove(self.chunk_idx);
+        self.tree.nodes[cont.node_idx as usize].chunk[cont.child_idx as usize] = ChunkPtr::None;
+        (cont.position, cont.chunk)
+    }
+}
+
+impl<'a, const N: usize, const B: usize, C, L> VacantEntry<'a, N, B, C, L>
+where
+    C: Sized,
+    L: LodVec<N>,
+{
+    /// Returns position of this entry
+    #[inline]
+    pub fn key(&self) -> L {
+        self.position
+    }
+
+    /// Inserts the chunk at entry's position, creating intermediate nodes as needed.
+    /// Returns a mutable reference to the chunk.
+    #[inline]
+    pub fn insert(self, chunk: C) -> &'a mut C {
+        let tree = self.tree;
+        // continue walking from where entry() has stopped
+        let mut addr = self.addr;
+        let child_idx = loop {
+            addr = match tree.descend_inner(addr, self.position) {
+                ControlFlow::Continue(a) => a,
+                ControlFlow::Break(child_idx) => break child_idx,
+            };
+        };
+        let idx = tree.place_chunk(addr, child_idx, chunk);
+        &mut tree.chunks[idx].chunk
+    }
+}
+
 /// Construct an itreator that traverses a subtree in nodes that begins in start (including start itself).
 #[inline]
 pub fn traverse<'a, const B: usize>(
@@ -693,6 +2034,7 @@ where
 mod tests {
 
     use super::*;
+    use std::cmp::Ordering;
 
     struct TestChunk;
 
@@ -730,6 +2072,510 @@ mod tests {
             },
         );
     }
+    #[test]
+    fn lod_update_targets() {
+        let player = QuadVec::build(3, 3, 5);
+        let beacon = QuadVec::build(28, 28, 5);
+
+        // plain targets are the same as LodTargets with same detail
+        let mut tree = QuadTree::<QuadVec, QuadVec>::new();
+        tree.lod_update(&[player, beacon], 2, |p| p, |_, _| {});
+        let mut tree2 = QuadTree::<QuadVec, QuadVec>::new();
+        let targets = [LodTarget::new(player, 2), LodTarget::new(beacon, 2)];
+        tree2.lod_update_targets(&targets, |p| p, |_, _| {});
+        assert_eq!(tree.get_num_chunks(), tree2.get_num_chunks());
+        for (_, c) in tree.iter_chunks() {
+            assert_eq!(tree2.get_chunk_by_position(c.position()), Some(&c.chunk));
+        }
+
+        // beacon only wants a coarse preview around it
+        let targets = [
+            LodTarget::new(player, 2),
+            LodTarget::new(beacon, 1).with_max_depth(3),
+        ];
+        tree2.lod_update_targets(&targets, |p| p, |_, _| {});
+        assert!(tree2.get_chunk_by_position(player).is_some());
+        assert!(tree2.get_chunk_by_position(beacon).is_none());
+        assert!(tree2
+            .get_chunk_by_position(beacon.ancestor_at_depth(3).unwrap())
+            .is_some());
+        let max_depth_near_beacon = tree2
+            .iter_chunks()
+            .map(|(_, c)| c.position())
+            .filter(|p| p.pos[0] >= 1 << (p.depth - 1))
+            .map(|p| p.depth)
+            .max();
+        assert_eq!(max_depth_near_beacon, Some(3));
+    }
+
+    #[test]
+    fn two_phase_update() {
+        let mut tree = QuadTree::<QuadVec, QuadVec>::new();
+        let mut tree2 = QuadTree::<QuadVec, QuadVec>::new();
+        let paths = [
+            [QuadVec::build(3, 3, 5), QuadVec::build(20, 9, 5)],
+            [QuadVec::build(5, 3, 5), QuadVec::build(10, 4, 4)],
+            [QuadVec::build(31, 31, 5), QuadVec::build(0, 0, 3)],
+        ];
+        for targets in paths {
+            let mut evicted = vec![];
+            tree.lod_update(&targets, 1, |p| p, |p, _| evicted.push(p));
+
+            let plan = tree2.prepare_update(&targets, 1);
+            assert!(!plan.is_empty());
+            let chunks: Vec<_> = plan.to_create.clone();
+            let mut evicted2 = vec![];
+            let mut to_evict = plan.to_evict.clone();
+            tree2
+                .do_update(plan, chunks, |p, c| {
+                    assert_eq!(p, c);
+                    evicted2.push(p)
+                })
+                .unwrap();
+
+            // same outcome as with the single-phase update
+            assert_eq!(tree.get_num_chunks(), tree2.get_num_chunks());
+            for (_, c) in tree2.iter_chunks() {
+                assert_eq!(c.chunk, c.position());
+                assert!(tree.get_chunk_by_position(c.position()).is_some());
+            }
+            for list in [&mut evicted, &mut evicted2, &mut to_evict] {
+                list.sort_by_key(|p| (p.depth, p.pos));
+            }
+            assert_eq!(evicted, evicted2);
+            assert_eq!(evicted, to_evict);
+
+            // and nothing left to do after that
+            assert!(tree2.prepare_update(&targets, 1).is_empty());
+        }
+    }
+
+    #[test]
+    fn stale_update_plan() {
+        let targets = [QuadVec::build(3u8, 3, 4)];
+        let mut tree = QuadTree::<QuadVec, QuadVec>::new();
+        tree.lod_update(&[QuadVec::build(12u8, 12, 4)], 1, |p| p, |_, _| {});
+        let num_chunks = tree.get_num_chunks();
+
+        // tree changed after the plan was made
+        let plan = tree.prepare_update(&targets, 1);
+        let chunks = plan.to_create.clone();
+        tree.insert(plan.to_create[0], |p| p);
+        assert_eq!(
+            tree.do_update(plan, chunks, |_, _| {}),
+            Err(LodUpdateError::StalePlan)
+        );
+        // nothing was touched
+        assert_eq!(tree.get_num_chunks(), num_chunks + 1);
+        for (_, c) in tree.iter_chunks() {
+            assert_eq!(tree.get_chunk_by_position(c.position()), Some(&c.chunk));
+        }
+
+        // too many chunks
+        let plan = tree.prepare_update(&targets, 1);
+        let mut chunks = plan.to_create.clone();
+        chunks.push(QuadVec::root());
+        let expected = plan.to_create.len();
+        assert_eq!(
+            tree.do_update(plan, chunks, |_, _| {}),
+            Err(LodUpdateError::ChunkCountMismatch {
+                expected,
+                found: expected + 1
+            })
+        );
+        assert_eq!(tree.get_num_chunks(), num_chunks + 1);
+
+        // plans do not carry over to clones or other trees
+        let deep = QuadVec::build(63u8, 63, 6);
+        tree.insert(deep, |p| p);
+        tree.pop_chunk_by_position(deep);
+        let plan = tree.prepare_update(&targets, 1);
+        let chunks = plan.to_create.clone();
+        let mut clone = tree.clone();
+        assert_eq!(
+            clone.do_update(plan.clone(), chunks.clone(), |_, _| {}),
+            Err(LodUpdateError::WrongTree)
+        );
+        let mut other = QuadTree::<QuadVec, QuadVec>::new();
+        assert_eq!(
+            other.do_update(plan.clone(), chunks.clone(), |_, _| {}),
+            Err(LodUpdateError::WrongTree)
+        );
+
+        // a fresh plan still works, even after dropping the nodes left empty
+        let num_nodes = tree.nodes.len();
+        tree.prune_nodes();
+        assert!(tree.nodes.len() < num_nodes);
+        tree.do_update(plan, chunks, |_, _| {}).unwrap();
+        assert!(tree.prepare_update(&targets, 1).is_empty());
+    }
+
+    #[test]
+    fn lod_update_with_cache() {
+        let mut tree = QuadTree::<QuadVec, QuadVec>::new();
+        tree.set_cache(Some(ChunkCache::new(1000)));
+        let here = [QuadVec::build(3, 3, 5)];
+        let there = [QuadVec::build(28, 20, 5)];
+        let positions = |t: &QuadTree<QuadVec, QuadVec>| {
+            t.iter_chunks()
+                .map(|(_, c)| c.position())
+                .collect::<std::collections::HashSet<_>>()
+        };
+
+        let mut created = 0;
+        let mut evicted = 0;
+        tree.lod_update(&here, 1, |p| p, |_, _| evicted += 1);
+        let here_positions = positions(&tree);
+        tree.lod_update(&there, 1, |p| p, |_, _| evicted += 1);
+        assert_eq!(evicted, 0);
+        let there_positions = positions(&tree);
+        assert_eq!(
+            tree.cache().unwrap().len(),
+            here_positions.difference(&there_positions).count()
+        );
+
+        // coming back should not create anything new
+        tree.cache_mut().unwrap().reset_stats();
+        tree.lod_update(
+            &here,
+            1,
+            |p| {
+                created += 1;
+                p
+            },
+            |_, _| evicted += 1,
+        );
+        assert_eq!(created, 0);
+        assert_eq!(positions(&tree), here_positions);
+        let cache = tree.cache().unwrap();
+        assert_eq!(
+            cache.hits() as usize,
+            here_positions.difference(&there_positions).count()
+        );
+        assert_eq!(cache.misses() as usize, 0);
+        for (_, c) in tree.iter_chunks() {
+            assert_eq!(c.chunk, c.position());
+        }
+
+        // two-phase update takes same chunks from the cache
+        let plan = tree.prepare_update(&there, 1);
+        assert!(plan.to_create.is_empty());
+        assert!(!plan.from_cache.is_empty());
+        tree.do_update(plan, [], |_, _| evicted += 1).unwrap();
+        assert_eq!(evicted, 0);
+        assert_eq!(positions(&tree), there_positions);
+        for (_, c) in tree.iter_chunks() {
+            assert_eq!(c.chunk, c.position());
+        }
+
+        // with a tiny cache, chunks that do not fit go to evict_callback
+        let old = tree.set_cache(Some(ChunkCache::new(2))).unwrap();
+        assert_eq!(
+            old.len(),
+            here_positions.difference(&there_positions).count()
+        );
+        tree.lod_update(&here, 1, |p| p, |_, _| evicted += 1);
+        assert_eq!(tree.cache().unwrap().len(), 2);
+        assert_eq!(
+            evicted + 2,
+            there_positions.difference(&here_positions).count()
+        );
+    }
+
+    #[test]
+    fn lod_transitions() {
+        let mut tree = QuadTree::<(), QuadVec>::new();
+        let targets = [QuadVec::build(5, 6, 4)];
+        tree.lod_update(&targets, 0, |_| (), |_, _| {});
+
+        let mut seen = LodTransitions::default();
+        for (_, c, t) in tree.iter_chunks_with_transitions() {
+            let pos = c.position();
+            for face in 0..4 {
+                let Some(n) = pos.neighbor(face_offset(face)) else {
+                    assert!(!t.is_coarser(face) && !t.is_finer(face));
+                    continue;
+                };
+                // chunk covering the neighbor cell is coarser, else there must be finer chunks inside it
+                let coarser = n
+                    .path_from_root()
+                    .skip(1)
+                    .any(|a| a.depth < pos.depth && tree.get_chunk_by_position(a).is_some());
+                let finer = tree.get_chunk_by_position(n).is_none() && !coarser;
+                assert_eq!(t.is_coarser(face), coarser, "{pos:?} face {face}");
+                assert_eq!(t.is_finer(face), finer, "{pos:?} face {face}");
+            }
+            seen.coarser |= t.coarser;
+            seen.finer |= t.finer;
+        }
+        // all kinds of transitions should be present in this tree
+        assert_eq!((seen.coarser, seen.finer), (0b1111, 0b1111));
+
+        // same answers as find_neighbor_chunks, including overlapping chunks
+        use rand::{rngs::SmallRng, Rng, SeedableRng};
+        let mut rng = SmallRng::seed_from_u64(42);
+        for _ in 0..200 {
+            let depth = rng.gen_range(1..6u8);
+            let max = (1u8 << depth) - 1;
+            tree.insert(
+                QuadVec::build(rng.gen_range(0..=max), rng.gen_range(0..=max), depth),
+                |_| (),
+            );
+        }
+        for (_, c, t) in tree.iter_chunks_with_transitions() {
+            let pos = c.position();
+            for face in 0..4 {
+                let found = tree.find_neighbor_chunks(pos, face_offset(face));
+                let finer = found.iter().any(|n| n.pos.depth > pos.depth);
+                let coarser = found.iter().any(|n| n.pos.depth < pos.depth);
+                assert_eq!(t.is_finer(face), finer, "{pos:?} face {face}");
+                assert_eq!(t.is_coarser(face), coarser, "{pos:?} face {face}");
+            }
+        }
+    }
+
+    #[test]
+    fn lod_balance() {
+        // largest depth difference between adjacent chunks, in given directions
+        fn max_jump(tree: &QuadTree<(), QuadVec>, directions: &[[i64; 2]]) -> u8 {
+            let mut rv = 0;
+            for (_, c) in tree.iter_chunks() {
+                let pos = c.position();
+                for &d in directions {
+                    for n in tree.find_neighbor_chunks(pos, d) {
+                        rv = rv.max(n.pos.depth.abs_diff(pos.depth));
+                    }
+                }
+            }
+            rv
+        }
+        let faces: Vec<[i64; 2]> = (0..4).map(face_offset).collect();
+        let all: Vec<[i64; 2]> = [-1, 0, 1]
+            .into_iter()
+            .flat_map(|x| [[x, -1], [x, 0], [x, 1]])
+            .filter(|&d| d != [0, 0])
+            .collect();
+        let targets = [QuadVec::build(21, 42, 6)];
+
+        let mut tree = QuadTree::<(), QuadVec>::new();
+        tree.lod_update(&targets, 0, |_| (), |_, _| {});
+        assert!(max_jump(&tree, &faces) > 1);
+        let unbalanced = tree.get_num_chunks();
+
+        tree.set_lod_balance(LodBalance::Faces);
+        tree.lod_update(&targets, 0, |_| (), |_, _| {});
+        assert_eq!(max_jump(&tree, &faces), 1);
+        assert!(tree.get_num_chunks() > unbalanced);
+        // the target still gets its detail
+        assert!(tree.get_chunk_by_position(targets[0]).is_some());
+
+        tree.set_lod_balance(LodBalance::Full);
+        tree.lod_update(&targets, 0, |_| (), |_, _| {});
+        assert_eq!(max_jump(&tree, &all), 1);
+
+        // two-phase update honors the constraint too
+        let mut tree2 = QuadTree::<(), QuadVec>::new();
+        tree2.set_lod_balance(LodBalance::Full);
+        let plan = tree2.prepare_update(&targets, 0);
+        let chunks = vec![(); plan.to_create.len()];
+        tree2.do_update(plan, chunks, |_, _| {}).unwrap();
+        let positions = |t: &QuadTree<(), QuadVec>| {
+            let mut v: Vec<_> = t.iter_chunks().map(|(_, c)| c.position()).collect();
+            v.sort_by(QuadVec::morton_cmp);
+            v
+        };
+        assert_eq!(positions(&tree), positions(&tree2));
+    }
+
+    #[test]
+    fn merge() {
+        let a_pos = [
+            QuadVec::build(0u8, 0, 1),
+            QuadVec::build(2, 3, 2),
+            QuadVec::build(5, 1, 3),
+            QuadVec::build(9, 9, 4),
+        ];
+        let b_pos = [
+            QuadVec::build(0u8, 0, 1),
+            QuadVec::build(5, 1, 3),
+            QuadVec::build(10, 3, 4),
+            QuadVec::build(30, 30, 5),
+        ];
+        let mut a = QuadTree::<u32, QuadVec>::new();
+        a.insert_many(a_pos.iter().copied(), |_| 1);
+        let mut b = QuadTree::<u32, QuadVec>::new();
+        b.insert_many(b_pos.iter().copied(), |_| 10);
+
+        let mut conflicts = Vec::new();
+        a.merge(b, |pos, mine, theirs| {
+            conflicts.push(pos);
+            mine + theirs
+        });
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(a.get_num_chunks(), 6);
+        for p in a_pos.iter().chain(b_pos.iter()) {
+            let expected = match (a_pos.contains(p), b_pos.contains(p)) {
+                (true, true) => 11,
+                (true, false) => 1,
+                _ => 10,
+            };
+            assert_eq!(a.get_chunk_by_position(*p), Some(&expected));
+        }
+        // chunk back-references must be intact
+        a.defragment_chunks();
+        a.defragment_nodes();
+        assert_eq!(a.get_chunk_by_position(b_pos[3]), Some(&10));
+
+        // merging into an empty tree
+        let mut c = QuadTree::<u32, QuadVec>::new();
+        c.merge(a, |_, _, _| unreachable!());
+        assert_eq!(c.get_num_chunks(), 6);
+        assert_eq!(c.get_chunk_by_position(a_pos[0]), Some(&11));
+    }
+
+    #[test]
+    fn split_off_graft() {
+        let mut tree = QuadTree::<u32, QuadVec>::new();
+        let region = QuadVec::build(1u8, 0, 2);
+        let inside = [
+            QuadVec::build(1u8, 0, 2),
+            QuadVec::build(2, 1, 3),
+            QuadVec::build(13, 7, 5),
+        ];
+        let outside = [QuadVec::build(0u8, 0, 1), QuadVec::build(4, 4, 3)];
+        tree.insert_many(inside.iter().chain(outside.iter()).copied(), |p| {
+            p.depth as u32
+        });
+
+        let mut part = tree.split_off(region);
+        assert_eq!(part.get_num_chunks(), inside.len());
+        assert_eq!(tree.get_num_chunks(), outside.len());
+        for p in inside {
+            assert_eq!(part.get_chunk_by_position(p), Some(&(p.depth as u32)));
+            assert_eq!(tree.get_chunk_by_position(p), None);
+        }
+        // nothing left there
+        assert_eq!(tree.split_off(region).get_num_chunks(), 0);
+
+        // the subtree can be worked on separately, then put back
+        *part.get_chunk_by_position_mut(inside[2]).unwrap() = 42;
+        let old = tree.graft(region, part);
+        assert_eq!(old.get_num_chunks(), 0);
+        assert_eq!(tree.get_num_chunks(), inside.len() + outside.len());
+        assert_eq!(tree.get_chunk_by_position(inside[2]), Some(&42));
+        tree.defragment_chunks();
+        tree.prune_nodes();
+        tree.defragment_nodes();
+        assert_eq!(tree.get_chunk_by_position(inside[1]), Some(&3));
+
+        // grafting over existing data hands it back
+        let mut other = QuadTree::<u32, QuadVec>::new();
+        other.insert(inside[1], |_| 7);
+        let old = tree.graft(region, other);
+        assert_eq!(old.get_num_chunks(), inside.len());
+        assert_eq!(tree.get_num_chunks(), outside.len() + 1);
+        assert_eq!(tree.get_chunk_by_position(inside[1]), Some(&7));
+        assert_eq!(tree.get_chunk_by_position(inside[0]), None);
+    }
+
+    #[test]
+    fn retain() {
+        let mut tree = QuadTree::<u32, QuadVec>::new();
+        tree.lod_update(
+            &[QuadVec::build(5u8, 6, 4)],
+            1,
+            |p| p.pos[0] as u32,
+            |_, _| {},
+        );
+        let total = tree.get_num_chunks();
+        let odd = tree.iter_chunks().filter(|(_, c)| c.chunk & 1 == 1).count();
+
+        // reference result made the slow way
+        let mut reference = tree.clone();
+        let even: Vec<_> = reference
+            .iter_chunks()
+            .filter(|(_, c)| c.chunk & 1 == 0)
+            .map(|(_, c)| c.position())
+            .collect();
+        for p in even {
+            reference.pop_chunk_by_position(p);
+        }
+        reference.prune_nodes();
+
+        let mut visited = 0;
+        tree.retain(|pos, c| {
+            visited += 1;
+            assert_eq!(pos.pos[0] as u32, *c);
+            *c += 100;
+            *c & 1 == 1
+        });
+        assert_eq!(visited, total);
+        assert_eq!(tree.get_num_chunks(), odd);
+        assert_eq!(tree.nodes.len(), reference.nodes.len());
+        for (_, c) in reference.iter_chunks() {
+            assert_eq!(
+                tree.get_chunk_by_position(c.position()),
+                Some(&(c.chunk + 100))
+            );
+        }
+
+        tree.retain(|_, _| false);
+        assert_eq!(tree.get_num_chunks(), 0);
+        assert_eq!(tree.nodes.len(), 1);
+    }
+
+    #[test]
+    fn from_sorted() {
+        use rand::{rngs::SmallRng, Rng, SeedableRng};
+        let mut rng = SmallRng::seed_from_u64(42);
+        let positions: Vec<QuadVec> = (0..300)
+            .map(|_| {
+                let depth = rng.gen_range(1..7u8);
+                let max = (1u8 << depth) - 1;
+                QuadVec::build(rng.gen_range(0..=max), rng.gen_range(0..=max), depth)
+            })
+            .collect();
+        let mut reference = QuadTree::<usize, QuadVec>::new();
+        for (i, &p) in positions.iter().enumerate() {
+            reference.insert(p, |_| i);
+        }
+
+        let tree = QuadTree::from_unsorted(positions.iter().copied().zip(0..));
+        assert_eq!(tree.get_num_chunks(), reference.get_num_chunks());
+        assert_eq!(tree.nodes.len(), reference.nodes.len());
+        // sparse input with duplicates still gets exact capacities
+        assert_eq!(tree.nodes.capacity(), tree.nodes.len());
+        assert_eq!(tree.chunks.capacity(), tree.chunks.len());
+        for (_, c) in reference.iter_chunks() {
+            assert_eq!(tree.get_chunk_by_position(c.position()), Some(&c.chunk));
+        }
+        // chunks are laid out in Morton order
+        assert!(tree
+            .iter_chunks()
+            .zip(tree.iter_chunks().skip(1))
+            .all(|((_, a), (_, b))| a.position().morton_cmp(&b.position()) == Ordering::Less));
+
+        // memory for a dense tree is allocated once, with nothing to spare
+        let mut dense: Vec<_> = crate::iter::iter_all_positions_in_bounds(
+            QuadVec::build(0u8, 0, 5),
+            QuadVec::build(31u8, 31, 5),
+        )
+        .filter(|p| p.depth == 5)
+        .map(|p| (p, 0))
+        .collect();
+        dense.sort_by(|a, b| a.0.morton_cmp(&b.0));
+        let tree = QuadTree::<usize, QuadVec>::from_sorted(dense);
+        assert_eq!(tree.get_num_chunks(), 1024);
+        assert_eq!(tree.nodes.capacity(), tree.nodes.len());
+        assert_eq!(tree.chunks.capacity(), tree.chunks.len());
+
+        // empty input is fine, for insert_many too
+        let mut tree = QuadTree::<usize, QuadVec>::from_sorted(std::iter::empty());
+        assert_eq!(tree.get_num_chunks(), 0);
+        tree.insert_many(std::iter::empty(), |_| 0);
+        assert_eq!(tree.nodes.len(), 1);
+    }
+
     #[test]
     fn insert_into_tree() {
         // make a tree
@@ -750,6 +2596,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn entry() {
+        let mut tree = QuadTree::<usize, QuadVec>::new();
+        let pos = QuadVec::build(2u8, 3u8, 2u8);
+        // vacant entries do not create nodes until something is inserted
+        match tree.entry(pos) {
+            Entry::Vacant(e) => assert_eq!(e.key(), pos),
+            Entry::Occupied(_) => panic!("Tree is empty"),
+        }
+        assert_eq!(tree.nodes.len(), 1);
+
+        *tree.entry(pos).or_insert(1) += 1;
+        assert_eq!(tree.get_chunk_by_position(pos), Some(&2));
+        assert_eq!(tree.nodes.len(), 2);
+
+        // modify existing chunk
+        let v = tree.entry(pos).and_modify(|c| *c *= 10).or_default();
+        assert_eq!(*v, 20);
+        // and_modify does nothing to vacant entries
+        let other = QuadVec::build(0u8, 1u8, 2u8);
+        let v = tree
+            .entry(other)
+            .and_modify(|c| *c = 100)
+            .or_insert_with_key(|p| p.pos[1] as usize);
+        assert_eq!(*v, 1);
+        assert_eq!(tree.get_num_chunks(), 2);
+
+        // upper level chunk in a node that already exists
+        let upper = QuadVec::build(1u8, 1u8, 1u8);
+        tree.entry(upper).or_insert_with(|| 5);
+        assert_eq!(tree.nodes.len(), 3);
+
+        match tree.entry(pos) {
+            Entry::Occupied(e) => {
+                assert_eq!(e.key(), pos);
+                assert_eq!(e.remove(), 20);
+            }
+            Entry::Vacant(_) => panic!("Chunk should be there"),
+        }
+        assert!(tree.get_chunk_by_position(pos).is_none());
+        assert_eq!(tree.get_num_chunks(), 2);
+        assert!(matches!(tree.entry(pos), Entry::Vacant(_)));
+    }
+
     #[test]
     pub fn defragment() {
         let mut tree = QuadTree::<TestChunk, QuadVec>::new();
@@ -770,9 +2660,144 @@ mod tests {
         assert_eq!(tree.chunks.capacity(), tree.chunks.len());
         dbg!(tree.nodes.len());
         tree.pop_chunk_by_position(targets[0]);
-        //TODO!
-        //tree.prune();
+        tree.prune_nodes();
+        tree.defragment_nodes();
         dbg!(tree.nodes.len());
+        assert_eq!(tree.nodes.len(), 2);
+        for t in &targets[2..] {
+            assert!(tree.get_chunk_by_position(*t).is_some());
+        }
+    }
+
+    #[test]
+    pub fn defragment_morton() {
+        use rand::{rngs::SmallRng, Rng, SeedableRng};
+        let mut rng = SmallRng::seed_from_u64(42);
+        let mut tree = QuadTree::<QuadVec, QuadVec>::new();
+        for _ in 0..200 {
+            let d = rng.gen_range(1..6);
+            let pos = QuadVec::new([rng.gen_range(0..1 << d), rng.gen_range(0..1 << d)], d);
+            tree.insert(pos, |p| p);
+        }
+        // make some holes
+        for _ in 0..50 {
+            let idx = rng.gen_range(0..tree.chunks.capacity());
+            if let Some(pos) = tree.get_chunk_position(idx) {
+                tree.pop_chunk_by_position(pos);
+            }
+        }
+        let num_chunks = tree.get_num_chunks();
+        tree.defragment_chunks_morton();
+
+        assert_eq!(tree.get_num_chunks(), num_chunks);
+        assert!(tree.iter_chunks().map(|(i, _)| i).eq(0..num_chunks));
+        for (i, c) in tree.iter_chunks() {
+            assert_eq!(c.chunk, c.position());
+            assert_eq!(
+                tree.nodes[c.node_idx as usize].chunk[c.child_idx as usize].get(),
+                Some(i)
+            );
+            assert_eq!(tree.get_chunk_by_position(c.position()), Some(&c.chunk));
+        }
+        let positions: Vec<_> = tree.iter_chunks().map(|(_, c)| c.position()).collect();
+        assert!(positions
+            .windows(2)
+            .all(|w| w[0].morton_cmp(&w[1]) == Ordering::Less));
+    }
+
+    #[test]
+    pub fn prune() {
+        let mut tree = OctTree::<usize, OctVec>::new();
+        let targets = [
+            OctVec::build(0u8, 0, 0, 4),
+            OctVec::build(15u8, 15, 15, 4),
+            OctVec::build(15u8, 0, 15, 4),
+            OctVec::build(1u8, 1, 1, 1),
+        ];
+        tree.insert_many(targets.iter().copied(), |p| p.pos[0] as usize);
+        let nodes_before = tree.nodes.len();
+        // nothing to prune yet
+        tree.prune_nodes();
+        assert_eq!(tree.nodes.len(), nodes_before);
+
+        // removing a deep chunk leaves a dead branch of 3 nodes behind
+        tree.pop_chunk_by_position(targets[0]);
+        assert_eq!(tree.nodes.len(), nodes_before);
+        tree.prune_nodes();
+        assert_eq!(tree.nodes.len(), nodes_before - 3);
+
+        // remaining chunks must be still reachable, and know their nodes
+        for t in &targets[1..] {
+            assert_eq!(*tree.get_chunk_by_position(*t).unwrap(), t.pos[0] as usize);
+        }
+        for (_, c) in tree.iter_chunks() {
+            let node = &tree.nodes[c.node_idx as usize];
+            assert!(node.chunk[c.child_idx as usize].get().is_some());
+        }
+
+        // empty tree should have just the root left
+        for t in &targets[1..] {
+            tree.pop_chunk_by_position(*t);
+        }
+        tree.prune_nodes();
+        assert_eq!(tree.nodes.len(), 1);
+        assert!(tree.nodes[0].is_empty());
+    }
+
+    #[test]
+    pub fn neighbor_chunks() {
+        let mut tree = QuadTree::<(), QuadVec>::new();
+        let targets = [QuadVec::build(3, 3, 4), QuadVec::build(12, 5, 4)];
+        tree.lod_update(&targets, 0, |_| (), |_, _| {});
+
+        // brute force: chunks whose face lies on the given face of pos, with overlapping extents
+        let adjacent = |pos: QuadVec, dir: [i64; 2], other: QuadVec| {
+            let (pl, ps) = (pos.float_coords(), pos.float_size());
+            let (ol, os) = (other.float_coords(), other.float_size());
+            (0..2).all(|i| match dir[i] {
+                1 => ol[i] == pl[i] + ps,
+                -1 => ol[i] + os == pl[i],
+                _ => ol[i] < pl[i] + ps && pl[i] < ol[i] + os,
+            })
+        };
+        for c in tree.iter_chunks().map(|(_, c)| c.position()) {
+            for dir in [[1, 0], [-1, 0], [0, 1], [0, -1]] {
+                let mut found: Vec<_> = tree
+                    .find_neighbor_chunks(c, dir)
+                    .into_iter()
+                    .map(|p| {
+                        assert_eq!(tree.get_chunk(p.idx).position(), p.pos);
+                        p.pos
+                    })
+                    .collect();
+                let mut expected: Vec<_> = tree
+                    .iter_chunks()
+                    .map(|(_, o)| o.position())
+                    .filter(|&o| adjacent(c, dir, o))
+                    .collect();
+                found.sort_by_key(|p| (p.depth, p.pos));
+                expected.sort_by_key(|p| (p.depth, p.pos));
+                assert_eq!(found, expected, "{c:?} {dir:?}");
+            }
+        }
+
+        // a coarse chunk next to a fine one is found from both sides
+        let (fine, coarse) = tree
+            .iter_chunks()
+            .map(|(_, c)| c.position())
+            .find_map(|c| {
+                let n = tree.find_neighbor_chunks(c, [1, 0]);
+                (n.len() == 1 && n[0].pos.depth < c.depth).then(|| (c, n[0].pos))
+            })
+            .expect("LOD update should produce mixed depths");
+        assert!(tree
+            .find_neighbor_chunks(coarse, [-1, 0])
+            .iter()
+            .any(|p| p.pos == fine));
+        // nothing beyond the border
+        assert!(tree
+            .find_neighbor_chunks(QuadVec::build(0, 0, 4), [-1, 0])
+            .is_empty());
     }
 
     #[test]
@@ -114,9 +114,13 @@ impl<const B: usize> TreeNode<B> {
         }
     }
 
+    /// Iterates over (child slot, chunk index) pairs of chunks that are present in this node
     #[inline]
     pub fn iter_existing_chunks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
-        self.chunk.iter().filter_map(|c| c.get()).enumerate()
+        self.chunk
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| Some((i, c.get()?)))
     }
 
     #[inline]
@@ -125,14 +129,15 @@ impl<const B: usize> TreeNode<B> {
     }
 }
 
+/// Iterates over (child slot, node index) pairs of children that are present
 #[inline]
 pub fn iter_treenode_children<const N: usize>(
     children: &[NodePtr; N],
 ) -> impl Iterator<Item = (usize, usize)> + '_ {
     children
         .iter()
-        .filter_map(|c| Some((*c)?.get() as usize))
         .enumerate()
+        .filter_map(|(i, c)| Some((i, (*c)?.get() as usize)))
 }
 
 // utility struct for holding actual chunks and the node that owns them